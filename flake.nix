@@ -93,6 +93,7 @@
         exec env \
           NPCNIX_AWS_CLI=''${NPCNIX_AWS_CLI:-${pkgs.awscli2}/bin/aws} \
           NPCNIX_NIXOS_REBUILD=''${NPCNIX_NIXOS_REBUILD:-${pkgs.nixos-rebuild}/bin/nixos-rebuild} \
+          NPCNIX_NIX_ENV=''${NPCNIX_NIX_ENV:-${pkgs.nix}/bin/nix-env} \
           PATH="${pkgs.git}/bin:$PATH" \
           ${multiBuild.npcnix}/bin/npcnix "$@"
       '';
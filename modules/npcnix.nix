@@ -16,15 +16,39 @@ with lib;
       default = self.outputs.packages.${pkgs.system}.npcnix;
       description = mdDoc "The package providing npcnix binary.";
     };
+
+    settings = mkOption {
+      type = types.attrsOf types.anything;
+      default = { };
+      example = literalExpression ''
+        {
+          remote = "s3://some-config/remotes/main";
+          configuration = "host";
+          max_sleep_secs = 300;
+        }
+      '';
+      description = mdDoc ''
+        Declarative npcnix settings written to `/etc/npcnix/config.json`.
+
+        Settings set here take precedence over the ones in the runtime
+        config in `/var/lib/npcnix/config.json` (e.g. set with `npcnix config set`).
+        Settings are merged per top-level key: e.g. setting `activate` here
+        replaces the whole runtime `activate` table, not only the fields given.
+      '';
+    };
   };
 
   config = mkIf config.npcnix.enable {
     environment.systemPackages = [ config.npcnix.package ];
 
+    environment.etc."npcnix/config.json" = mkIf (config.npcnix.settings != { }) {
+      text = builtins.toJSON config.npcnix.settings;
+    };
+
     systemd.services.npcnix = {
       # restart after successful activation to reload itself, without blocking/terminating whole system activation
       script = ''
-        ${config.npcnix.package}/bin/npcnix follow --once=activate
+        exec ${config.npcnix.package}/bin/npcnix follow --once=activate
       '';
 
       wantedBy = [ "multi-user.target" ];
@@ -35,8 +59,14 @@ with lib;
       restartIfChanged = false; # we don't want to kill daemon currently running `nixos-rebuild`
 
       serviceConfig = {
+        Type = "notify";
+        # restart a daemon stuck e.g. talking to the remote; builds and switches don't count
+        WatchdogSec = "10min";
         Restart = "always";
         RestartSec = 15;
+        # only signal npcnix itself on stop; it lets an in-progress `nixos-rebuild` finish
+        KillMode = "mixed";
+        TimeoutStopSec = "30min";
       };
     };
   };
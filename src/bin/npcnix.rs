@@ -6,7 +6,7 @@ use std::path::PathBuf;
 
 use clap::{Parser, Subcommand, ValueEnum};
 use npcnix::data_dir::DataDir;
-use tracing::trace;
+use tracing::{info, trace};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, Layer};
@@ -34,10 +34,49 @@ pub enum Command {
         #[command(subcommand)]
         command: Option<ConfigOpts>,
     },
-    /// Status
-    Status,
+    /// Show status of npcnix on this host
+    Status {
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show daemon runtime state
+    State,
+    /// Show recorded activation attempts
+    History {
+        /// Show only the last N activations
+        #[arg(long, short = 'n')]
+        limit: Option<usize>,
+
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the output of a recorded activation
+    Log {
+        /// Activation id (see `history`), or `last`
+        #[arg(default_value = "last")]
+        id: String,
+    },
     /// Activate a NixOS configuration from a Nix Flake in a local directory
     Activate(ActivateOpts),
+    /// Build a configuration and show what activating it would change
+    Plan {
+        /// Source directory (default: the current version of the remote)
+        #[arg(long)]
+        src: Option<PathBuf>,
+
+        /// Configuration to plan (default: the one `follow` would activate)
+        #[arg(long)]
+        configuration: Option<String>,
+
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+
+        #[command(flatten)]
+        activate: ActivateCommonOpts,
+    },
     /// Pack a Nix Flake in a local directory into a remote-like packed Nix
     /// Flake file
     Pack(PackOpts),
@@ -51,15 +90,166 @@ pub enum Command {
     /// Run as a daemon periodically activating NixOS configuration from the
     /// remote
     Follow(FollowOpts),
+    /// Re-activate a previously activated remote version from the local cache
+    /// and pin it
+    Rollback {
+        /// Etag to roll back to (default: the one activated before the
+        /// current one)
+        #[arg(long)]
+        to: Option<String>,
+
+        #[command(flatten)]
+        activate: ActivateCommonOpts,
+    },
+    /// Make the running daemon check the remote immediately
+    Trigger,
+    /// Abort the fetch/build the running daemon is doing
+    Abort,
     /// Permanently or temporarily pause the npcnix daemon
     Pause(PauseOpts),
     /// Unpause the npcnix daemon
     Unpause,
+    /// Hold the current (or given) remote version, even if the remote changes
+    Pin {
+        /// Etag to hold (default: last activated one)
+        #[arg(long)]
+        etag: Option<String>,
+    },
+    /// Resume following the remote after `pin`
+    Unpin,
+    /// Fleet-wide commands
+    Fleet {
+        #[command(subcommand)]
+        command: FleetOpts,
+    },
+    /// Manage staged rollout of the remote (`<remote>.rollout.json`)
+    Rollout {
+        #[command(subcommand)]
+        command: RolloutOpts,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum FleetOpts {
+    /// Show status of all hosts reporting to a store
+    Status {
+        /// Store (location containing remotes) to read reports from, e.g.
+        /// `s3://bucket/remotes` (the trailing `/` is optional)
+        #[arg(long)]
+        store: Url,
+
+        /// Region to use for the store access (typically s3 bucket)
+        #[arg(long)]
+        remote_region: Option<String>,
+
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Pause all followers of a remote (or only ones using a given
+    /// configuration)
+    Pause {
+        #[command(flatten)]
+        remote: RemoteOpts,
+
+        /// Pause only followers of this configuration
+        #[arg(long)]
+        configuration: Option<String>,
+
+        #[command(flatten)]
+        pause: PauseOpts,
+    },
+    /// Unpause followers paused with `fleet pause`
+    Unpause {
+        #[command(flatten)]
+        remote: RemoteOpts,
+
+        /// Unpause only followers of this configuration
+        #[arg(long)]
+        configuration: Option<String>,
+    },
+    /// Keep followers of a configuration on a given remote version
+    Pin {
+        #[command(flatten)]
+        remote: RemoteOpts,
+
+        #[arg(long)]
+        configuration: String,
+
+        /// Etag to pin to (default: current etag of the remote)
+        #[arg(long)]
+        etag: Option<String>,
+    },
+    /// Remove pin set with `fleet pin`
+    Unpin {
+        #[command(flatten)]
+        remote: RemoteOpts,
+
+        #[arg(long)]
+        configuration: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum RolloutOpts {
+    /// Show current rollout policy
+    Show {
+        #[command(flatten)]
+        remote: RemoteOpts,
+    },
+    /// Set rollout policy
+    Set {
+        #[command(flatten)]
+        remote: RemoteOpts,
+
+        /// Apply the rollout to any remote version, not only the current one
+        #[arg(long)]
+        any_etag: bool,
+
+        /// Percentage of hosts to take the new version (initial percentage with
+        /// `--start`)
+        #[arg(long)]
+        percentage: Option<f64>,
+
+        /// Host id or hostname that should always take the new version (can be
+        /// specified multiple times)
+        #[arg(long = "host")]
+        hosts: Vec<String>,
+
+        /// Start of the ramp-up
+        #[arg(long)]
+        start: Option<chrono::DateTime<chrono::Utc>>,
+
+        /// Ramp up to 100% over this many hours after `--start`
+        #[arg(long, requires = "start")]
+        ramp_hours: Option<u64>,
+    },
+    /// Remove rollout policy, letting all hosts take the current version
+    Clear {
+        #[command(flatten)]
+        remote: RemoteOpts,
+    },
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct RemoteOpts {
+    /// Remote to manage
+    #[arg(long)]
+    remote: Url,
+
+    /// Region to use for the remote access (typically s3 bucket)
+    #[arg(long)]
+    remote_region: Option<String>,
 }
 
 #[derive(Subcommand, Debug, Clone)]
 pub enum ConfigOpts {
-    Show,
+    /// Show effective config values and where they came from
+    Show {
+        /// Print only the effective config as JSON
+        #[arg(long)]
+        json: bool,
+    },
     /// Change daemon settings
     Set {
         /// Only update if not already set
@@ -84,12 +274,49 @@ pub struct PullOpts {
 
 #[derive(Parser, Debug, Clone)]
 pub struct PauseOpts {
+    /// Pause for this long (e.g. `2h30m`, `1d`)
+    #[arg(long = "for", group("duration"), value_parser = npcnix::misc::parse_duration)]
+    for_duration: Option<chrono::Duration>,
+
+    /// Pause until given time (e.g. `2026-11-01T09:00Z`)
+    #[arg(long, group("duration"), value_parser = npcnix::misc::parse_datetime)]
+    until: Option<chrono::DateTime<chrono::Utc>>,
+
     /// Pause for this many hours
     #[arg(long, group("duration"))]
     hours: Option<u64>,
 
+    /// Pause for this many minutes
     #[arg(long, group("duration"))]
     minutes: Option<u64>,
+
+    /// Reason to display to operators
+    #[arg(long)]
+    reason: Option<String>,
+}
+
+impl PauseOpts {
+    /// End of the pause; `None` means indefinitely
+    fn until(&self) -> anyhow::Result<Option<chrono::DateTime<chrono::Utc>>> {
+        let duration = if let Some(until) = self.until {
+            return Ok(Some(until));
+        } else if let Some(duration) = self.for_duration {
+            duration
+        } else if let Some(minutes) = self.minutes {
+            chrono::Duration::from_std(std::time::Duration::from_secs(minutes.saturating_mul(60)))?
+        } else if let Some(hours) = self.hours {
+            chrono::Duration::from_std(std::time::Duration::from_secs(
+                hours.saturating_mul(60 * 60),
+            ))?
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(
+            chrono::Utc::now()
+                .checked_add_signed(duration)
+                .ok_or_else(|| anyhow::format_err!("Pause duration too long"))?,
+        ))
+    }
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -99,6 +326,23 @@ pub struct ActivateCommonOpts {
 
     #[arg(long)]
     extra_trusted_public_keys: Vec<String>,
+
+    /// Pass `--option <KEY> <VALUE>` to `nixos-rebuild` (can be specified
+    /// multiple times)
+    #[arg(long = "option", num_args = 2, value_names = ["KEY", "VALUE"])]
+    options: Vec<String>,
+
+    /// Pass `--max-jobs` to `nixos-rebuild`
+    #[arg(long)]
+    max_jobs: Option<String>,
+
+    /// Pass `--builders` to `nixos-rebuild`
+    #[arg(long)]
+    builders: Option<String>,
+
+    /// Pass `--cores` to `nixos-rebuild`
+    #[arg(long)]
+    cores: Option<u32>,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -142,6 +386,14 @@ impl From<ActivateCommonOpts> for npcnix::ActivateOpts {
         npcnix::ActivateOpts {
             extra_substituters: value.extra_substituters,
             extra_trusted_public_keys: value.extra_trusted_public_keys,
+            options: value
+                .options
+                .chunks_exact(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect(),
+            max_jobs: value.max_jobs,
+            builders: value.builders,
+            cores: value.cores,
         }
     }
 }
@@ -156,6 +408,10 @@ pub struct PackCommonOpts {
     /// all)
     #[arg(long)]
     include: Vec<OsString>,
+
+    /// Revision to record in the manifest (default: git commit of `src`)
+    #[arg(long)]
+    revision: Option<String>,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -180,8 +436,164 @@ pub struct PackOpts {
 
 #[derive(Subcommand, Debug, Clone)]
 pub enum SetOpts {
-    Remote { url: Url },
-    Configuration { configuration: String },
+    Remote {
+        url: Url,
+    },
+    /// Configuration to use; can be a template with `{hostname}`,
+    /// `{machine_id}` or `{instance_id}` placeholders
+    Configuration {
+        configuration: String,
+    },
+    /// Hosts file inside the flake mapping hostnames, machine ids or instance
+    /// ids to configurations
+    HostsFile {
+        path: PathBuf,
+    },
+    /// Configuration to use if no other selection rule matched
+    DefaultConfiguration {
+        configuration: String,
+    },
+    /// Log the changes (packages, closure size, units) before switching and
+    /// record them in the activation history
+    Plan {
+        #[arg(action = clap::ArgAction::Set)]
+        enabled: bool,
+    },
+    /// Publish status reports of this host next to the remote
+    Report {
+        #[arg(action = clap::ArgAction::Set)]
+        enabled: bool,
+    },
+    /// Export Prometheus metrics (replaces existing settings; no options
+    /// disable the export)
+    Metrics {
+        /// node_exporter textfile collector file to write
+        #[arg(long)]
+        textfile: Option<PathBuf>,
+
+        /// Local address to serve metrics over HTTP on, e.g. `127.0.0.1:9797`
+        #[arg(long)]
+        listen: Option<std::net::SocketAddr>,
+    },
+    /// Limit how many hosts can activate at the same time, fleet-wide
+    ActivationLimit {
+        /// Maximum number of simultaneous activations (0 to disable the limit)
+        limit: u32,
+
+        /// Limit group; hosts in different groups have separate limits
+        #[arg(long)]
+        group: Option<String>,
+
+        /// Lease duration after which a slot of a crashed host is released
+        #[arg(long)]
+        lease_secs: Option<u64>,
+    },
+    /// Settings passed to `nixos-rebuild` on every activation (replaces
+    /// existing ones)
+    Activate {
+        #[command(flatten)]
+        activate: ActivateCommonOpts,
+    },
+    /// What to do when the running system differs from the one activated by
+    /// npcnix
+    DriftPolicy {
+        #[arg(value_enum)]
+        policy: DriftPolicyOpts,
+    },
+    /// Activate remote versions, or only observe them
+    Mode {
+        #[command(subcommand)]
+        mode: ModeOpts,
+    },
+    /// What to do when an activation requires a reboot (kernel, initrd or
+    /// systemd changed)
+    RebootPolicy {
+        #[command(subcommand)]
+        policy: RebootPolicyOpts,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum DriftPolicyOpts {
+    /// Only report the drift
+    ReportOnly,
+    /// Re-activate the remote configuration
+    Reconverge,
+}
+
+impl From<DriftPolicyOpts> for npcnix::drift::DriftPolicy {
+    fn from(value: DriftPolicyOpts) -> Self {
+        match value {
+            DriftPolicyOpts::ReportOnly => npcnix::drift::DriftPolicy::ReportOnly,
+            DriftPolicyOpts::Reconverge => npcnix::drift::DriftPolicy::Reconverge,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ModeOpts {
+    /// Activate new remote versions
+    Activate,
+    /// Never activate; only evaluate new remote versions and report whether
+    /// the host is up to date
+    Observe {
+        /// Build the remote configuration, not only evaluate it
+        #[arg(long)]
+        build: bool,
+    },
+}
+
+impl From<ModeOpts> for npcnix::observe::FollowMode {
+    fn from(value: ModeOpts) -> Self {
+        match value {
+            ModeOpts::Activate => npcnix::observe::FollowMode::Activate,
+            ModeOpts::Observe { build } => npcnix::observe::FollowMode::Observe { build },
+        }
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum RebootPolicyOpts {
+    /// Never reboot, only report that reboot is required
+    Never,
+    /// Reboot during a daily maintenance window
+    Window {
+        /// Start of the window (UTC, `HH:MM`)
+        #[arg(long, value_parser = parse_time_of_day)]
+        start: chrono::NaiveTime,
+
+        /// Length of the window in minutes
+        #[arg(long)]
+        duration_mins: u64,
+    },
+    /// Reboot right after activation
+    Immediately {
+        /// Delay the reboot by this many minutes
+        #[arg(long, default_value = "0")]
+        delay_mins: u64,
+    },
+}
+
+impl From<RebootPolicyOpts> for npcnix::reboot::RebootPolicy {
+    fn from(value: RebootPolicyOpts) -> Self {
+        match value {
+            RebootPolicyOpts::Never => npcnix::reboot::RebootPolicy::Never,
+            RebootPolicyOpts::Window {
+                start,
+                duration_mins,
+            } => npcnix::reboot::RebootPolicy::MaintenanceWindow {
+                start,
+                duration_mins,
+            },
+            RebootPolicyOpts::Immediately { delay_mins } => {
+                npcnix::reboot::RebootPolicy::Immediately { delay_mins }
+            }
+        }
+    }
+}
+
+fn parse_time_of_day(s: &str) -> anyhow::Result<chrono::NaiveTime> {
+    Ok(chrono::NaiveTime::parse_from_str(s, "%H:%M")?)
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Default)]
@@ -225,6 +637,35 @@ impl FollowOpts {
         }
     }
 }
+impl RemoteOpts {
+    fn current_etag(&self) -> anyhow::Result<String> {
+        npcnix::get_etag(
+            &self.remote,
+            &npcnix::config::Config::default().with_remote_region(self.remote_region.as_deref()),
+        )
+    }
+}
+
+/// Read-modify-write the control document, retrying if it was changed
+/// concurrently
+fn update_control(
+    remote: &RemoteOpts,
+    f: impl Fn(npcnix::control::Control) -> npcnix::control::Control,
+) -> anyhow::Result<()> {
+    const ATTEMPTS: u32 = 10;
+    let region = remote.remote_region.as_deref();
+    for _ in 0..ATTEMPTS {
+        let (control, etag) = npcnix::control::Control::fetch_with_etag(&remote.remote, region)?;
+        if f(control.expire_paused()).store_if_unchanged(&remote.remote, etag.as_deref(), region)? {
+            return Ok(());
+        }
+        info!("Control document changed concurrently; retrying");
+    }
+    anyhow::bail!(
+        "Control document keeps changing concurrently; giving up after {ATTEMPTS} attempts"
+    )
+}
+
 pub fn tracing_init() -> anyhow::Result<()> {
     let filter_layer = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     let fmt_layer = tracing_subscriber::fmt::layer()
@@ -250,35 +691,116 @@ fn main() -> anyhow::Result<()> {
         Command::Push(ref push_opts) => npcnix::push(
             &push_opts.pack.src,
             &push_opts.clone().pack.include.into_iter().collect(),
+            push_opts.pack.revision.as_deref(),
             &push_opts.remote,
         )?,
         Command::Pack(ref pack_opts) => npcnix::pack(
             &pack_opts.pack.src,
             &pack_opts.clone().pack.include.into_iter().collect(),
+            pack_opts.pack.revision.as_deref(),
             &pack_opts.dst,
         )?,
         Command::Config { ref command } => match command {
-            Some(ConfigOpts::Show) | None => {
-                let _ = write!(std::io::stdout(), "{}\n", opts.data_dir().load_config()?);
+            Some(ConfigOpts::Show { json: true }) => {
+                let _ = writeln!(std::io::stdout(), "{}", opts.data_dir().load_config()?);
+            }
+            Some(ConfigOpts::Show { json: false }) | None => {
+                let _ = write!(
+                    std::io::stdout(),
+                    "{}",
+                    opts.data_dir().load_layered_config()?
+                );
             }
             Some(ConfigOpts::Set { init, ref value }) => match value {
-                SetOpts::Remote { ref url } => opts.data_dir().store_config(
-                    &opts
-                        .data_dir()
-                        .load_config()?
-                        .with_remote_maybe_init(url, *init),
-                )?,
-                SetOpts::Configuration { ref configuration } => opts.data_dir().store_config(
-                    &opts
-                        .data_dir()
-                        .load_config()?
-                        .with_configuration_maybe_init(configuration, *init),
-                )?,
+                SetOpts::Remote { ref url } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_remote_maybe_init(url, *init)))?,
+                SetOpts::Configuration { ref configuration } => {
+                    opts.data_dir().update_config(|config| {
+                        Ok(config.with_configuration_maybe_init(configuration, *init))
+                    })?
+                }
+                SetOpts::HostsFile { ref path } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_hosts_file_maybe_init(path, *init)))?,
+                SetOpts::DefaultConfiguration { ref configuration } => {
+                    opts.data_dir().update_config(|config| {
+                        Ok(config.with_default_configuration_maybe_init(configuration, *init))
+                    })?
+                }
+                SetOpts::Plan { enabled } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_plan(*enabled)))?,
+                SetOpts::Report { enabled } => opts
+                    .data_dir()
+                    .update_config(|config| Ok(config.with_report(*enabled)))?,
+                SetOpts::Metrics {
+                    ref textfile,
+                    listen,
+                } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_metrics(npcnix::metrics::MetricsConfig {
+                        textfile: textfile.clone(),
+                        listen: *listen,
+                    }))
+                })?,
+                SetOpts::ActivationLimit {
+                    limit,
+                    ref group,
+                    lease_secs,
+                } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_activation_limit((*limit != 0).then(|| {
+                        npcnix::lease::ActivationLimit::new(*limit, group.as_deref(), *lease_secs)
+                    })))
+                })?,
+                SetOpts::Activate { ref activate } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_activate_opts_maybe_init(activate.clone().into(), *init))
+                })?,
+                SetOpts::DriftPolicy { policy } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_drift_policy_maybe_init((*policy).into(), *init))
+                })?,
+                SetOpts::Mode { ref mode } => opts.data_dir().update_config(|config| {
+                    Ok(config.with_mode_maybe_init(mode.clone().into(), *init))
+                })?,
+                SetOpts::RebootPolicy { ref policy } => {
+                    opts.data_dir().update_config(|config| {
+                        Ok(config.with_reboot_policy_maybe_init(policy.clone().into(), *init))
+                    })?
+                }
             },
         },
-        Command::Status => {
-            let status_string = opts.data_dir().load_config()?.status_string();
-            let _ = write!(std::io::stdout(), "{}\n", status_string);
+        Command::Status { json } => {
+            let status = npcnix::status::HostStatus::collect(&opts.data_dir())?;
+            if json {
+                let _ = writeln!(
+                    std::io::stdout(),
+                    "{}",
+                    serde_json::to_string_pretty(&status)?
+                );
+            } else {
+                let _ = write!(std::io::stdout(), "{status}");
+            }
+        }
+        Command::State => {
+            let _ = writeln!(std::io::stdout(), "{}", opts.data_dir().load_state()?);
+        }
+        Command::History { limit, json } => {
+            let mut records = npcnix::history::list(&opts.data_dir())?;
+            if let Some(limit) = limit {
+                records.drain(..records.len().saturating_sub(limit));
+            }
+            if json {
+                let _ = writeln!(
+                    std::io::stdout(),
+                    "{}",
+                    serde_json::to_string_pretty(&records)?
+                );
+            } else {
+                let _ = write!(std::io::stdout(), "{}", npcnix::history::History(records));
+            }
+        }
+        Command::Log { ref id } => {
+            let path = npcnix::history::log_file(&opts.data_dir(), id)?;
+            std::io::copy(&mut std::fs::File::open(path)?, &mut std::io::stdout())?;
         }
         Command::Activate(ref activate_opts) => {
             if opts.data_dir().config_exist()? {
@@ -286,6 +808,7 @@ fn main() -> anyhow::Result<()> {
                     .data_dir()
                     .get_current_configuration_with_opt_override(
                         activate_opts.configuration.as_deref(),
+                        Some(&activate_opts.src),
                     )?;
                 npcnix::activate(
                     Some(&opts.data_dir()),
@@ -304,6 +827,28 @@ fn main() -> anyhow::Result<()> {
                 )?;
             }
         }
+        Command::Plan {
+            ref src,
+            ref configuration,
+            json,
+            ref activate,
+        } => {
+            let plan = npcnix::plan(
+                &opts.data_dir(),
+                src.as_deref(),
+                configuration.as_deref(),
+                &activate.clone().into(),
+            )?;
+            if json {
+                let _ = writeln!(
+                    std::io::stdout(),
+                    "{}",
+                    serde_json::to_string_pretty(&plan)?
+                );
+            } else {
+                let _ = write!(std::io::stdout(), "{plan}");
+            }
+        }
         Command::Follow(ref follow_opts) => {
             npcnix::follow(
                 &opts.data_dir(),
@@ -313,31 +858,156 @@ fn main() -> anyhow::Result<()> {
                 follow_opts.ignore_etag,
             )?;
         }
-        Command::Pause(PauseOpts { hours, minutes }) => {
-            let config = opts.data_dir().load_config()?;
-
-            let config = if let Some(minutes) = minutes {
-                config.with_paused_until(
-                    chrono::Utc::now()
-                        + chrono::Duration::seconds(TryFrom::try_from(minutes.saturating_mul(60))?),
-                )
-            } else if let Some(hours) = hours {
-                config.with_paused_until(
-                    chrono::Utc::now()
-                        + chrono::Duration::seconds(TryFrom::try_from(
-                            hours.saturating_mul(60 * 60),
-                        )?),
-                )
-            } else {
-                config.with_paused_indefinitely()
+        Command::Trigger => {
+            let socket = opts.data_dir().socket_path();
+            if npcnix::socket::request_ok(&socket, &npcnix::socket::Request::Trigger)?.is_none() {
+                npcnix::daemon::trigger(&opts.data_dir().pid_file_path())?;
+            }
+        }
+        Command::Rollback {
+            ref to,
+            ref activate,
+        } => npcnix::rollback(&opts.data_dir(), to.as_deref(), &activate.clone().into())?,
+        Command::Abort => {
+            let socket = opts.data_dir().socket_path();
+            if npcnix::socket::request_ok(&socket, &npcnix::socket::Request::Abort)?.is_none() {
+                anyhow::bail!("npcnix follow is not running");
+            }
+        }
+        Command::Pause(ref pause_opts) => {
+            let until = pause_opts.until()?;
+            let info = npcnix::config::PauseInfo::new(pause_opts.reason.as_deref());
+            let request = npcnix::socket::Request::Pause {
+                until,
+                info: info.clone(),
             };
-
-            opts.data_dir().store_config(&config)?;
+            if npcnix::socket::request_ok(&opts.data_dir().socket_path(), &request)?.is_none() {
+                opts.data_dir().update_config(|config| {
+                    Ok(match until {
+                        Some(until) => config.with_paused_until(until, info),
+                        None => config.with_paused_indefinitely(info),
+                    })
+                })?;
+            }
         }
         Command::Unpause => {
-            let config = opts.data_dir().load_config()?;
-            opts.data_dir().store_config(&config.with_unpaused())?;
+            let request = npcnix::socket::Request::Unpause;
+            if npcnix::socket::request_ok(&opts.data_dir().socket_path(), &request)?.is_none() {
+                opts.data_dir()
+                    .update_config(|config| Ok(config.with_unpaused()))?;
+            }
+        }
+        Command::Pin { ref etag } => {
+            let etag = match etag {
+                Some(etag) => etag.clone(),
+                None => opts.data_dir().load_state()?.last_etag().to_owned(),
+            };
+            if etag.is_empty() {
+                anyhow::bail!("Nothing activated yet; pass `--etag` explicitly");
+            }
+            opts.data_dir()
+                .update_config(|config| Ok(config.with_pinned(&etag)))?;
         }
+        Command::Unpin => {
+            opts.data_dir()
+                .update_config(|config| Ok(config.with_unpinned()))?;
+        }
+        Command::Fleet { ref command } => match command {
+            FleetOpts::Status {
+                ref store,
+                ref remote_region,
+                json,
+            } => {
+                let status = npcnix::report::FleetStatus::fetch(store, remote_region.as_deref())?;
+                if *json {
+                    let _ = writeln!(
+                        std::io::stdout(),
+                        "{}",
+                        serde_json::to_string_pretty(&status)?
+                    );
+                } else {
+                    let _ = write!(std::io::stdout(), "{status}");
+                }
+            }
+            FleetOpts::Pause {
+                ref remote,
+                ref configuration,
+                ref pause,
+            } => {
+                let until = pause.until()?;
+                update_control(remote, |control| {
+                    control.with_paused(
+                        configuration.as_deref(),
+                        npcnix::control::RemotePause {
+                            until,
+                            info: npcnix::config::PauseInfo::new(pause.reason.as_deref()),
+                        },
+                    )
+                })?
+            }
+            FleetOpts::Unpause {
+                ref remote,
+                ref configuration,
+            } => update_control(remote, |control| {
+                control.with_unpaused(configuration.as_deref())
+            })?,
+            FleetOpts::Pin {
+                ref remote,
+                ref configuration,
+                ref etag,
+            } => {
+                let etag = match etag {
+                    Some(etag) => etag.clone(),
+                    None => remote.current_etag()?,
+                };
+                update_control(remote, |control| {
+                    control.with_pinned(configuration, Some(&etag))
+                })?
+            }
+            FleetOpts::Unpin {
+                ref remote,
+                ref configuration,
+            } => update_control(remote, |control| control.with_pinned(configuration, None))?,
+        },
+        Command::Rollout { ref command } => match command {
+            RolloutOpts::Show { ref remote } => {
+                let rollout = npcnix::rollout::Rollout::fetch(
+                    &remote.remote,
+                    remote.remote_region.as_deref(),
+                )?;
+                let _ = writeln!(
+                    std::io::stdout(),
+                    "{}",
+                    serde_json::to_string_pretty(&rollout)?
+                );
+            }
+            RolloutOpts::Set {
+                ref remote,
+                any_etag,
+                percentage,
+                ref hosts,
+                start,
+                ramp_hours,
+            } => {
+                let etag = if *any_etag {
+                    None
+                } else {
+                    Some(remote.current_etag()?)
+                };
+                npcnix::rollout::Rollout {
+                    etag,
+                    percentage: *percentage,
+                    hosts: hosts.clone(),
+                    start: *start,
+                    ramp_hours: *ramp_hours,
+                }
+                .store(&remote.remote, remote.remote_region.as_deref())?;
+            }
+            RolloutOpts::Clear { ref remote } => npcnix::store::delete_object(
+                &npcnix::rollout::rollout_url(&remote.remote),
+                remote.remote_region.as_deref(),
+            )?,
+        },
         Command::Install(InstallOpts {
             ref remote,
             ref remote_region,
@@ -345,18 +1015,17 @@ fn main() -> anyhow::Result<()> {
             ref initial_configuration,
             ref activate,
         }) => {
-            opts.data_dir().store_config(
-                &opts
-                    .data_dir()
-                    .load_config()?
+            opts.data_dir().update_config(|config| {
+                Ok(config
                     .with_remote(remote)
                     .with_remote_region(remote_region.as_deref())
-                    .with_configuration(configuration),
-            )?;
+                    .with_configuration(configuration)
+                    .with_activate_opts(activate.clone().into()))
+            })?;
 
             npcnix::follow(
                 &opts.data_dir(),
-                &activate.clone().into(),
+                &Default::default(),
                 initial_configuration.as_deref(),
                 Some(npcnix::Once::Any),
                 false,
@@ -366,3 +1035,72 @@ fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn activate_opts_merge() {
+        let config: npcnix::config::Config = serde_json::from_str(
+            r#"{
+                "activate": {
+                    "extra_substituters": ["https://stored.cache"],
+                    "options": {"narinfo-cache-negative-ttl": "0", "connect-timeout": "5"},
+                    "max_jobs": "2",
+                    "cores": 4
+                }
+            }"#,
+        )
+        .unwrap();
+        let cli = ActivateCommonOpts::try_parse_from([
+            "npcnix",
+            "--extra-substituters",
+            "https://cli.cache",
+            "--option",
+            "connect-timeout",
+            "30",
+            "--max-jobs",
+            "8",
+            "--builders",
+            "ssh://builder",
+        ])
+        .unwrap();
+
+        // CLI flags take precedence over the stored config
+        let merged = config
+            .activate_opts()
+            .clone()
+            .merge(&npcnix::ActivateOpts::from(cli));
+        assert_eq!(
+            merged.nix_args(),
+            [
+                "--option",
+                "extra-substituters",
+                "https://stored.cache",
+                "--option",
+                "extra-substituters",
+                "https://cli.cache",
+                "--option",
+                "connect-timeout",
+                "30",
+                "--option",
+                "narinfo-cache-negative-ttl",
+                "0",
+                "--max-jobs",
+                "8",
+                "--builders",
+                "ssh://builder",
+                "--cores",
+                "4",
+            ]
+        );
+
+        // No CLI flags keep the stored config as is
+        let stored = config.activate_opts().clone();
+        assert_eq!(
+            stored.clone().merge(&npcnix::ActivateOpts::default()),
+            stored
+        );
+    }
+}
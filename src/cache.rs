@@ -0,0 +1,311 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::data_dir::DataDir;
+use crate::{daemon, metrics};
+
+/// Prefix of directories of downloads in progress
+const TMP_PREFIX: &str = ".tmp";
+const TMP_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Metadata of a cached source (`sources/<key>.json` in the data dir)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct CachedSource {
+    pub etag: String,
+    pub fetched: chrono::DateTime<chrono::Utc>,
+    pub last_used: chrono::DateTime<chrono::Utc>,
+}
+
+/// Unpacked flake sources of previously fetched remote versions, keyed by
+/// etag, allowing rollback and re-activation without the remote
+#[derive(Debug, Clone)]
+pub struct SourceCache {
+    dir: PathBuf,
+}
+
+impl SourceCache {
+    pub fn new(data_dir: &DataDir) -> Self {
+        Self {
+            dir: data_dir.sources_dir(),
+        }
+    }
+
+    /// File-system safe key of `etag`
+    fn key(etag: &str) -> String {
+        etag.chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .collect()
+    }
+
+    fn src_path(&self, etag: &str) -> PathBuf {
+        self.dir.join(Self::key(etag))
+    }
+
+    fn meta_path(&self, etag: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", Self::key(etag)))
+    }
+
+    /// Cached source of `etag`, if any, without marking it as used
+    pub fn peek(&self, etag: &str) -> Option<PathBuf> {
+        Some(self.src_path(etag)).filter(|path| path.is_dir() && self.meta_path(etag).exists())
+    }
+
+    /// Cached source of `etag`, if any
+    pub fn get(&self, etag: &str) -> anyhow::Result<Option<PathBuf>> {
+        let Some(mut meta) = self.load_meta(&self.meta_path(etag))? else {
+            return Ok(None);
+        };
+        let path = self.src_path(etag);
+        if !path.is_dir() {
+            return Ok(None);
+        }
+        meta.last_used = chrono::Utc::now();
+        crate::misc::store_json_pretty_to_file(&self.meta_path(etag), &meta)?;
+        Ok(Some(path))
+    }
+
+    /// Source of `etag`, pulled from the configured remote if not cached yet
+    ///
+    /// Fails if the remote changed during the download, so a concurrent push
+    /// never ends up cached under the previous etag.
+    pub fn fetch(&self, config: &Config, etag: &str) -> anyhow::Result<PathBuf> {
+        let remote = config.remote()?;
+        self.fetch_with(
+            etag,
+            |dst| metrics::track_remote("fetch", crate::pull(remote, dst)),
+            || metrics::track_remote("etag", crate::get_etag(remote, config)),
+        )
+    }
+
+    /// [`Self::fetch`] with the remote access abstracted out
+    fn fetch_with(
+        &self,
+        etag: &str,
+        pull: impl FnOnce(&Path) -> anyhow::Result<()>,
+        remote_etag: impl FnOnce() -> anyhow::Result<String>,
+    ) -> anyhow::Result<PathBuf> {
+        if let Some(path) = self.get(etag)? {
+            debug!(etag, path = %path.display(), "Using cached source");
+            return Ok(path);
+        }
+
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create cache dir: {}", self.dir.display()))?;
+        let tmp_dir = tempfile::Builder::new()
+            .prefix(TMP_PREFIX)
+            .tempdir_in(&self.dir)?;
+        daemon::set_phase(daemon::Phase::Fetching);
+        pull(tmp_dir.path())?;
+        let fetched_etag = remote_etag()?;
+        if fetched_etag != etag {
+            bail!("Remote changed during download (etag {etag} -> {fetched_etag}); will retry");
+        }
+
+        let path = self.src_path(etag);
+        remove_dir_if_exists(&path)?;
+        fs::rename(tmp_dir.keep(), &path)?;
+        let now = chrono::Utc::now();
+        crate::misc::store_json_pretty_to_file(
+            &self.meta_path(etag),
+            &CachedSource {
+                etag: etag.to_owned(),
+                fetched: now,
+                last_used: now,
+            },
+        )?;
+        Ok(path)
+    }
+
+    fn load_meta(&self, path: &Path) -> anyhow::Result<Option<CachedSource>> {
+        match fs::read(path) {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// All cached sources, most recently used first
+    pub fn list(&self) -> anyhow::Result<Vec<CachedSource>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut sources = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("json") {
+                sources.extend(self.load_meta(&path)?);
+            }
+        }
+        sources.sort_by_key(|source| std::cmp::Reverse(source.last_used));
+        Ok(sources)
+    }
+
+    /// Remove all but `keep` most recently used sources, except `protected`
+    /// ones
+    pub fn gc(&self, keep: usize, protected: &[&str]) -> anyhow::Result<()> {
+        for source in self.list()?.into_iter().skip(keep) {
+            if protected.contains(&source.etag.as_str()) {
+                continue;
+            }
+            info!(etag = source.etag, "Removing cached source");
+            remove_dir_if_exists(&self.src_path(&source.etag))?;
+            fs::remove_file(self.meta_path(&source.etag))?;
+        }
+        self.gc_tmp(TMP_MAX_AGE)
+    }
+
+    /// Remove leftovers of downloads interrupted by a crash, older than
+    /// `max_age`
+    fn gc_tmp(&self, max_age: Duration) -> anyhow::Result<()> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let now = SystemTime::now();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().starts_with(TMP_PREFIX) {
+                continue;
+            }
+            // Don't remove a download that might still be in progress
+            let modified = entry.metadata()?.modified()?;
+            if now.duration_since(modified).unwrap_or_default() < max_age {
+                continue;
+            }
+            debug!(path = %entry.path().display(), "Removing stale download");
+            remove_dir_if_exists(&entry.path())?;
+        }
+        Ok(())
+    }
+}
+
+fn remove_dir_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pull_ok(dst: &Path) -> anyhow::Result<()> {
+        fs::write(dst.join("flake.nix"), "{}")?;
+        Ok(())
+    }
+
+    fn cache(dir: &tempfile::TempDir) -> SourceCache {
+        SourceCache::new(&DataDir::new(dir.path()))
+    }
+
+    fn tmp_dirs(cache: &SourceCache) -> Vec<PathBuf> {
+        fs::read_dir(&cache.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with(TMP_PREFIX)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fetch_caches() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = cache(&dir);
+        let path = cache
+            .fetch_with("\"a\"", pull_ok, || Ok("\"a\"".into()))
+            .unwrap();
+        assert!(path.join("flake.nix").exists());
+        assert_eq!(cache.peek("\"a\"").as_deref(), Some(path.as_path()));
+
+        // Served from the cache, without touching the remote
+        let cached = cache
+            .fetch_with(
+                "\"a\"",
+                |_| panic!("pulled again"),
+                || panic!("etag read again"),
+            )
+            .unwrap();
+        assert_eq!(cached, path);
+    }
+
+    #[test]
+    fn fetch_rechecks_etag() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = cache(&dir);
+        let err = cache
+            .fetch_with("a", pull_ok, || Ok("b".into()))
+            .unwrap_err();
+        assert!(err.to_string().contains("Remote changed during download"));
+        assert!(cache.peek("a").is_none());
+        assert!(cache.list().unwrap().is_empty());
+        assert!(tmp_dirs(&cache).is_empty());
+
+        // Failed download doesn't leave anything behind either
+        assert!(cache
+            .fetch_with("a", |_| anyhow::bail!("network down"), || Ok("a".into()))
+            .is_err());
+        assert!(cache.peek("a").is_none());
+        assert!(tmp_dirs(&cache).is_empty());
+    }
+
+    #[test]
+    fn gc_keeps_recently_used_and_protected() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = cache(&dir);
+        for etag in ["a", "b", "c", "d"] {
+            cache.fetch_with(etag, pull_ok, || Ok(etag.into())).unwrap();
+        }
+        // Most recently used first: a, d, c, b
+        cache.get("a").unwrap().unwrap();
+        assert_eq!(
+            cache
+                .list()
+                .unwrap()
+                .iter()
+                .map(|source| source.etag.as_str())
+                .collect::<Vec<_>>(),
+            ["a", "d", "c", "b"]
+        );
+
+        cache.gc(2, &["b"]).unwrap();
+        assert!(cache.peek("a").is_some());
+        assert!(cache.peek("d").is_some());
+        assert!(cache.peek("c").is_none());
+        assert!(cache.peek("b").is_some());
+        assert!(cache.get("c").unwrap().is_none());
+    }
+
+    #[test]
+    fn gc_removes_stale_downloads() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = cache(&dir);
+        cache.fetch_with("a", pull_ok, || Ok("a".into())).unwrap();
+        let stale = cache.dir.join(format!("{TMP_PREFIX}interrupted"));
+        fs::create_dir_all(stale.join("src")).unwrap();
+
+        // Might still be in progress
+        cache.gc(10, &[]).unwrap();
+        assert!(stale.exists());
+
+        cache.gc_tmp(Duration::ZERO).unwrap();
+        assert!(!stale.exists());
+        assert!(cache.peek("a").is_some());
+    }
+}
@@ -1,12 +1,22 @@
-use std::path::Path;
-use std::{cmp, fmt, thread};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{cmp, fmt, fs};
 
-use anyhow::format_err;
+use anyhow::{format_err, Context};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 use url::Url;
 
+use crate::daemon::Wake;
+use crate::drift::DriftPolicy;
+use crate::lease::ActivationLimit;
+use crate::metrics::MetricsConfig;
+use crate::observe::FollowMode;
+use crate::reboot::RebootPolicy;
+use crate::state::State;
+use crate::ActivateOpts;
+
 fn default_min_sleep_secs() -> u64 {
     5
 }
@@ -18,6 +28,19 @@ fn default_max_sleep_secs() -> u64 {
 fn default_max_sleep_after_hours() -> u64 {
     24
 }
+
+fn default_max_failure_backoff_secs() -> u64 {
+    6 * 60 * 60
+}
+
+fn default_max_history() -> usize {
+    50
+}
+
+fn default_max_cached_sources() -> usize {
+    5
+}
+
 #[derive(Serialize, Deserialize, Debug, Copy, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -49,25 +72,131 @@ impl ConfigPaused {
     }
 }
 
+/// Who paused, when and why
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct PauseInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl PauseInfo {
+    /// New pause info, authored by the current user, now
+    pub fn new(reason: Option<&str>) -> Self {
+        Self {
+            reason: reason.map(ToOwned::to_owned),
+            author: crate::misc::current_user(),
+            created: Some(chrono::Utc::now()),
+        }
+    }
+}
+
+impl fmt::Display for PauseInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref author) = self.author {
+            write!(f, " by {author}")?;
+        }
+        if let Some(created) = self.created {
+            write!(
+                f,
+                " at {}",
+                created.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            )?;
+        }
+        if let Some(ref reason) = self.reason {
+            write!(f, ": {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// [`ConfigPaused`] along with its [`PauseInfo`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigPause {
+    #[serde(flatten)]
+    pub paused: ConfigPaused,
+    #[serde(flatten)]
+    pub info: PauseInfo,
+}
+
+/// Local hold on a given remote version
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ConfigPinned {
+    pub etag: String,
+    pub since: chrono::DateTime<chrono::Utc>,
+}
+
 /// Persistent config (`/var/lib/npcnix/config.json`)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct Config {
     remote: Option<Url>,
     remote_region: Option<String>,
+    /// Configuration name, possibly a template (see
+    /// [`crate::host::render_template`])
     configuration: Option<String>,
-    last_reconfiguration: chrono::DateTime<chrono::Utc>,
-    last_etag: String,
-    last_configuration: String,
+    /// Path of a hosts file inside the flake, mapping hosts to configurations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hosts_file: Option<PathBuf>,
+    /// Configuration to use if no other selection rule matched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_configuration: Option<String>,
     #[serde(default = "default_min_sleep_secs")]
     min_sleep_secs: u64,
     #[serde(default = "default_max_sleep_secs")]
     max_sleep_secs: u64,
     #[serde(default = "default_max_sleep_after_hours")]
     max_sleep_after_hours: u64,
+    #[serde(default = "default_max_failure_backoff_secs")]
+    max_failure_backoff_secs: u64,
+    /// How many activation records (and logs) to keep
+    #[serde(default = "default_max_history")]
+    max_history: usize,
+    /// How many unpacked remote versions to keep for rollback
+    #[serde(default = "default_max_cached_sources")]
+    max_cached_sources: usize,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    paused: Option<ConfigPaused>,
+    paused: Option<ConfigPause>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pinned: Option<ConfigPinned>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reboot_policy: Option<RebootPolicy>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    drift_policy: Option<DriftPolicy>,
+
+    /// Activate remote versions, or only observe them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<FollowMode>,
+
+    /// Log (and record in the activation history) a plan of the changes
+    /// before switching
+    #[serde(default)]
+    plan: bool,
+
+    /// Publish status reports of this host next to the remote
+    #[serde(default)]
+    report: bool,
+
+    /// Fleet-wide limit of simultaneous activations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    activation_limit: Option<ActivationLimit>,
+
+    /// Settings passed to `nixos-rebuild` on every activation
+    #[serde(default, skip_serializing_if = "ActivateOpts::is_empty")]
+    activate: ActivateOpts,
+
+    /// Prometheus metrics export
+    #[serde(default, skip_serializing_if = "MetricsConfig::is_empty")]
+    metrics: MetricsConfig,
 }
 
 impl Default for Config {
@@ -76,17 +205,159 @@ impl Default for Config {
             remote: None,
             remote_region: None,
             configuration: None,
-            last_reconfiguration: chrono::Utc::now(),
-            last_etag: "".into(),
-            last_configuration: "".into(),
+            hosts_file: None,
+            default_configuration: None,
             min_sleep_secs: default_min_sleep_secs(),
             max_sleep_secs: default_max_sleep_secs(),
             max_sleep_after_hours: default_max_sleep_after_hours(),
+            max_failure_backoff_secs: default_max_failure_backoff_secs(),
+            max_history: default_max_history(),
+            max_cached_sources: default_max_cached_sources(),
             paused: None,
+            pinned: None,
+            reboot_policy: None,
+            drift_policy: None,
+            mode: None,
+            plan: false,
+            report: false,
+            activation_limit: None,
+            activate: ActivateOpts::default(),
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+/// Where the effective value of a config setting came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// Built-in default
+    Default,
+    /// Mutable runtime config (`/var/lib/npcnix/config.json`)
+    Runtime(PathBuf),
+    /// Read-only declarative config (e.g. `/etc/npcnix/config.toml`)
+    Declarative(PathBuf),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => f.write_str("default"),
+            ConfigSource::Runtime(path) => write!(f, "runtime ({})", path.display()),
+            ConfigSource::Declarative(path) => write!(f, "declarative ({})", path.display()),
         }
     }
 }
 
+/// Load a config layer as a json object
+///
+/// Files with a `.json` extension are parsed as JSON, everything else as TOML.
+pub fn load_config_layer(
+    path: &Path,
+) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let value: serde_json::Value = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&content)?
+    } else {
+        toml::from_str(&content)?
+    };
+    match value {
+        serde_json::Value::Object(map) => Ok(map),
+        _ => anyhow::bail!("Config file {} is not an object", path.display()),
+    }
+}
+
+/// [`Config`] merged from multiple layers, along with the source of each
+/// setting
+///
+/// Precedence (highest first): declarative config, runtime config, built-in
+/// defaults.
+///
+/// Layers are merged per top-level key: a key set in a higher layer replaces
+/// the whole value of a lower one, so e.g. a declarative `activate` table
+/// hides every runtime `activate.*` field, not only the ones it sets.
+///
+/// Activation hooks (commands run before/after a switch) are out of scope
+/// and not configurable; use NixOS activation scripts or systemd units
+/// instead.
+#[derive(Debug, Clone)]
+pub struct LayeredConfig {
+    config: Config,
+    sources: BTreeMap<String, ConfigSource>,
+}
+
+impl LayeredConfig {
+    pub fn load(runtime: Option<&Path>, declarative: Option<&Path>) -> anyhow::Result<Self> {
+        let mut merged = serde_json::Map::new();
+        let mut sources = BTreeMap::new();
+
+        for (path, source) in [
+            (
+                runtime,
+                runtime.map(|p| ConfigSource::Runtime(p.to_owned())),
+            ),
+            (
+                declarative,
+                declarative.map(|p| ConfigSource::Declarative(p.to_owned())),
+            ),
+        ] {
+            let (Some(path), Some(source)) = (path, source) else {
+                continue;
+            };
+            for (key, value) in load_config_layer(path)? {
+                sources.insert(key.clone(), source.clone());
+                merged.insert(key, value);
+            }
+        }
+
+        let config =
+            serde_json::from_value::<Config>(serde_json::Value::Object(merged))?.expire_paused();
+
+        if let serde_json::Value::Object(effective) = serde_json::to_value(&config)? {
+            sources.retain(|key, _| effective.contains_key(key));
+            for key in effective.keys() {
+                sources.entry(key.clone()).or_insert(ConfigSource::Default);
+            }
+        }
+
+        Ok(Self { config, sources })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn into_config(self) -> Config {
+        self.config
+    }
+
+    pub fn source(&self, key: &str) -> &ConfigSource {
+        self.sources.get(key).unwrap_or(&ConfigSource::Default)
+    }
+
+    /// Keys set by the declarative config
+    pub fn declarative_keys(&self) -> impl Iterator<Item = &str> {
+        self.sources
+            .iter()
+            .filter(|(_, source)| matches!(source, ConfigSource::Declarative(_)))
+            .map(|(key, _)| key.as_str())
+    }
+}
+
+impl fmt::Display for LayeredConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let serde_json::Value::Object(effective) =
+            serde_json::to_value(&self.config).map_err(|_e| fmt::Error)?
+        else {
+            return Err(fmt::Error);
+        };
+        for (key, value) in effective {
+            writeln!(f, "{key} = {value}  # {}", self.source(&key))?;
+        }
+        Ok(())
+    }
+}
+
 impl Config {
     pub fn load(path: &Path) -> anyhow::Result<Self> {
         Ok(serde_json::from_reader::<_, Self>(std::fs::File::open(path)?)?.expire_paused())
@@ -124,6 +395,40 @@ impl Config {
         }
     }
 
+    pub fn with_hosts_file(self, hosts_file: &Path) -> Self {
+        Self {
+            hosts_file: Some(hosts_file.to_owned()),
+            ..self
+        }
+    }
+
+    /// Like [`Self::with_hosts_file`] but if `init` is `true` will not
+    /// overwrite the existing value
+    pub fn with_hosts_file_maybe_init(self, hosts_file: &Path, init: bool) -> Self {
+        if !init || self.hosts_file.is_none() {
+            self.with_hosts_file(hosts_file)
+        } else {
+            self
+        }
+    }
+
+    pub fn with_default_configuration(self, configuration: &str) -> Self {
+        Self {
+            default_configuration: Some(configuration.into()),
+            ..self
+        }
+    }
+
+    /// Like [`Self::with_default_configuration`] but if `init` is `true` will
+    /// not overwrite the existing value
+    pub fn with_default_configuration_maybe_init(self, configuration: &str, init: bool) -> Self {
+        if !init || self.default_configuration.is_none() {
+            self.with_default_configuration(configuration)
+        } else {
+            self
+        }
+    }
+
     pub fn with_remote(self, remote: &Url) -> Self {
         Self {
             remote: Some(remote.clone()),
@@ -138,25 +443,122 @@ impl Config {
         }
     }
 
-    pub fn with_paused_until(self, until: chrono::DateTime<chrono::Utc>) -> Self {
+    pub fn with_reboot_policy(self, reboot_policy: RebootPolicy) -> Self {
+        Self {
+            reboot_policy: Some(reboot_policy),
+            ..self
+        }
+    }
+
+    /// Like [`Self::with_reboot_policy`] but if `init` is `true` will not
+    /// overwrite the existing value
+    pub fn with_reboot_policy_maybe_init(self, reboot_policy: RebootPolicy, init: bool) -> Self {
+        if !init || self.reboot_policy.is_none() {
+            self.with_reboot_policy(reboot_policy)
+        } else {
+            self
+        }
+    }
+
+    pub fn with_activate_opts(self, activate: ActivateOpts) -> Self {
+        Self { activate, ..self }
+    }
+
+    /// Like [`Self::with_activate_opts`] but if `init` is `true` will not
+    /// overwrite the existing value
+    pub fn with_activate_opts_maybe_init(self, activate: ActivateOpts, init: bool) -> Self {
+        if !init || self.activate.is_empty() {
+            self.with_activate_opts(activate)
+        } else {
+            self
+        }
+    }
+
+    pub fn with_metrics(self, metrics: MetricsConfig) -> Self {
+        Self { metrics, ..self }
+    }
+
+    pub fn with_plan(self, plan: bool) -> Self {
+        Self { plan, ..self }
+    }
+
+    pub fn with_report(self, report: bool) -> Self {
+        Self { report, ..self }
+    }
+
+    pub fn with_activation_limit(self, activation_limit: Option<ActivationLimit>) -> Self {
+        Self {
+            activation_limit,
+            ..self
+        }
+    }
+
+    pub fn with_drift_policy(self, drift_policy: DriftPolicy) -> Self {
+        Self {
+            drift_policy: Some(drift_policy),
+            ..self
+        }
+    }
+
+    /// Like [`Self::with_drift_policy`] but if `init` is `true` will not
+    /// overwrite the existing value
+    pub fn with_drift_policy_maybe_init(self, drift_policy: DriftPolicy, init: bool) -> Self {
+        if !init || self.drift_policy.is_none() {
+            self.with_drift_policy(drift_policy)
+        } else {
+            self
+        }
+    }
+
+    pub fn with_mode(self, mode: FollowMode) -> Self {
+        Self {
+            mode: Some(mode),
+            ..self
+        }
+    }
+
+    /// Like [`Self::with_mode`] but if `init` is `true` will not overwrite
+    /// the existing value
+    pub fn with_mode_maybe_init(self, mode: FollowMode, init: bool) -> Self {
+        if !init || self.mode.is_none() {
+            self.with_mode(mode)
+        } else {
+            self
+        }
+    }
+
+    /// Pause until a given time; if already paused for longer, only `info`
+    /// is updated
+    pub fn with_paused_until(self, until: chrono::DateTime<chrono::Utc>, info: PauseInfo) -> Self {
         let until = ConfigPaused::Until { until };
         Self {
-            paused: Some(
-                self.paused
-                    .map(|current| current.combine(until))
+            paused: Some(ConfigPause {
+                paused: self
+                    .paused
+                    .map(|current| current.paused.combine(until))
                     .unwrap_or(until),
-            ),
+                info,
+            }),
             ..self
         }
     }
 
-    pub fn with_paused_indefinitely(self) -> Self {
+    pub fn with_paused_indefinitely(self, info: PauseInfo) -> Self {
         Self {
-            paused: Some(ConfigPaused::Indefinitely),
+            paused: Some(ConfigPause {
+                paused: ConfigPaused::Indefinitely,
+                info,
+            }),
             ..self
         }
     }
 
+    pub fn paused(&self) -> Option<&ConfigPause> {
+        self.paused
+            .as_ref()
+            .filter(|paused| !paused.paused.is_expired())
+    }
+
     pub fn with_unpaused(self) -> Self {
         Self {
             paused: None,
@@ -164,20 +566,39 @@ impl Config {
         }
     }
 
+    pub fn with_pinned(self, etag: &str) -> Self {
+        Self {
+            pinned: Some(ConfigPinned {
+                etag: etag.to_owned(),
+                since: chrono::Utc::now(),
+            }),
+            ..self
+        }
+    }
+
+    pub fn with_unpinned(self) -> Self {
+        Self {
+            pinned: None,
+            ..self
+        }
+    }
+
+    pub fn pinned(&self) -> Option<&ConfigPinned> {
+        self.pinned.as_ref()
+    }
+
     pub fn is_paused(&self) -> bool {
-        self.paused
-            .map(|paused| !paused.is_expired())
-            .unwrap_or(false)
+        self.paused().is_some()
     }
 
     pub fn status_string(&self) -> String {
-        match self.paused {
-            Some(paused) if !paused.is_expired() => match paused {
-                ConfigPaused::Indefinitely => "paused (indefinitely)".to_string(),
+        match self.paused() {
+            Some(ConfigPause { paused, info }) => match paused {
+                ConfigPaused::Indefinitely => format!("paused (indefinitely){info}"),
                 ConfigPaused::Until { until } => {
                     let duration = until.signed_duration_since(Utc::now());
                     format!(
-                        "paused (until {}; <={}h)",
+                        "paused (until {}; <={}h){info}",
                         until.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
                         duration.num_hours() + 1
                     )
@@ -197,15 +618,6 @@ impl Config {
         }
     }
 
-    pub fn with_updated_last_reconfiguration(self, configuration: &str, etag: &str) -> Self {
-        Self {
-            last_configuration: configuration.to_owned(),
-            last_etag: etag.to_owned(),
-            last_reconfiguration: chrono::Utc::now(),
-            ..self
-        }
-    }
-
     pub fn remote(&self) -> anyhow::Result<&Url> {
         self.remote
             .as_ref()
@@ -216,18 +628,74 @@ impl Config {
         self.remote_region.as_deref()
     }
 
-    pub fn configuration(&self) -> anyhow::Result<&str> {
-        self.configuration
-            .as_deref()
-            .ok_or_else(|| format_err!("configuration not set"))
+    /// Raw `configuration` setting; use [`crate::host::select_configuration`]
+    /// to get the configuration to activate
+    pub fn configuration_template(&self) -> Option<&str> {
+        self.configuration.as_deref()
+    }
+
+    pub fn hosts_file(&self) -> Option<&Path> {
+        self.hosts_file.as_deref()
+    }
+
+    pub fn default_configuration(&self) -> Option<&str> {
+        self.default_configuration.as_deref()
+    }
+
+    pub fn activate_opts(&self) -> &ActivateOpts {
+        &self.activate
+    }
+
+    pub fn metrics(&self) -> &MetricsConfig {
+        &self.metrics
+    }
+
+    pub fn plan(&self) -> bool {
+        self.plan
+    }
+
+    pub fn report(&self) -> bool {
+        self.report
+    }
+
+    pub fn activation_limit(&self) -> Option<&ActivationLimit> {
+        self.activation_limit.as_ref()
+    }
+
+    pub fn drift_policy(&self) -> DriftPolicy {
+        self.drift_policy.unwrap_or_default()
+    }
+
+    pub fn mode(&self) -> FollowMode {
+        self.mode.unwrap_or_default()
+    }
+
+    pub fn reboot_policy(&self) -> RebootPolicy {
+        self.reboot_policy.unwrap_or_default()
     }
 
-    pub fn cur_rng_sleep_time(&self) -> chrono::Duration {
+    pub fn max_sleep_secs(&self) -> u64 {
+        self.max_sleep_secs
+    }
+
+    pub fn max_failure_backoff_secs(&self) -> u64 {
+        self.max_failure_backoff_secs
+    }
+
+    pub fn max_history(&self) -> usize {
+        self.max_history
+    }
+
+    pub fn max_cached_sources(&self) -> usize {
+        self.max_cached_sources
+    }
+
+    pub fn cur_rng_sleep_time(&self, state: &State) -> chrono::Duration {
         use rand::Rng;
 
         let since_last_update = cmp::max(
             chrono::Duration::seconds(1),
-            chrono::Utc::now() - self.last_reconfiguration,
+            chrono::Utc::now() - state.last_reconfiguration(),
         );
 
         let duration_ratio = (since_last_update.num_seconds() as f32
@@ -245,18 +713,14 @@ impl Config {
         chrono::Duration::seconds(cmp::max(self.min_sleep_secs as i64, rnd_time as i64))
     }
 
-    pub fn rng_sleep(&self) {
-        let duration = self.cur_rng_sleep_time();
+    /// Sleep until the next check, unless woken up earlier
+    pub fn rng_sleep(&self, state: &State, wake: &Wake) {
+        let duration = self.cur_rng_sleep_time(state);
         debug!(duration = %duration, "Sleeping");
-        thread::sleep(duration.to_std().expect("Can't be negative"));
-    }
-
-    pub fn last_configuration(&self) -> &str {
-        &self.last_configuration
-    }
-
-    pub fn last_etag(&self) -> &str {
-        &self.last_etag
+        crate::daemon::set_phase(crate::daemon::Phase::Sleeping {
+            until: chrono::Utc::now() + duration,
+        });
+        wake.sleep(duration.to_std().expect("Can't be negative"));
     }
 }
 
@@ -265,3 +729,93 @@ impl fmt::Display for Config {
         f.write_str(&serde_json::to_string_pretty(self).map_err(|_e| fmt::Error)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layered_config() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let runtime = dir.path().join("config.json");
+        let declarative = dir.path().join("config.toml");
+        fs::write(
+            &runtime,
+            r#"{
+                "remote": "s3://bucket/remotes/main",
+                "configuration": "runtime-host",
+                "max_sleep_secs": 600,
+                "activate": {"max_jobs": "4", "cores": 2}
+            }"#,
+        )
+        .unwrap();
+        fs::write(
+            &declarative,
+            r#"
+                configuration = "declarative-host"
+                max_sleep_secs = 300
+
+                [activate]
+                builders = "ssh://builder"
+            "#,
+        )
+        .unwrap();
+
+        let layered = LayeredConfig::load(Some(&runtime), Some(&declarative)).unwrap();
+        let config = layered.config();
+        assert_eq!(
+            config.remote().unwrap().as_str(),
+            "s3://bucket/remotes/main"
+        );
+        assert_eq!(config.configuration.as_deref(), Some("declarative-host"));
+        assert_eq!(config.max_sleep_secs(), 300);
+        // Whole top-level keys are replaced, not merged
+        assert_eq!(
+            config.activate_opts(),
+            &ActivateOpts {
+                builders: Some("ssh://builder".into()),
+                ..ActivateOpts::default()
+            }
+        );
+
+        assert_eq!(
+            layered.source("remote"),
+            &ConfigSource::Runtime(runtime.clone())
+        );
+        assert_eq!(
+            layered.source("configuration"),
+            &ConfigSource::Declarative(declarative.clone())
+        );
+        assert_eq!(
+            layered.source("activate"),
+            &ConfigSource::Declarative(declarative.clone())
+        );
+        assert_eq!(layered.source("min_sleep_secs"), &ConfigSource::Default);
+        assert_eq!(
+            layered.declarative_keys().collect::<Vec<_>>(),
+            ["activate", "configuration", "max_sleep_secs"]
+        );
+
+        let shown = layered.to_string();
+        assert!(shown.contains(&format!(
+            "configuration = \"declarative-host\"  # declarative ({})",
+            declarative.display()
+        )));
+        assert!(shown.contains("min_sleep_secs = 5  # default"));
+    }
+
+    #[test]
+    fn layered_config_runtime_only() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let runtime = dir.path().join("config.json");
+        fs::write(&runtime, r#"{"configuration": "host"}"#).unwrap();
+
+        let layered = LayeredConfig::load(Some(&runtime), None).unwrap();
+        assert_eq!(layered.config().configuration.as_deref(), Some("host"));
+        assert_eq!(
+            layered.source("configuration"),
+            &ConfigSource::Runtime(runtime)
+        );
+        assert_eq!(layered.declarative_keys().count(), 0);
+    }
+}
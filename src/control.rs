@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::config::PauseInfo;
+use crate::store;
+
+/// Suffix of the control document object, next to the remote
+pub const CONTROL_SUFFIX: &str = ".control.json";
+
+pub fn control_url(remote: &Url) -> Url {
+    store::sibling_url(remote, CONTROL_SUFFIX)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct RemotePause {
+    /// Pause expiry; paused indefinitely if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(flatten)]
+    pub info: PauseInfo,
+}
+
+impl RemotePause {
+    pub fn is_active(&self) -> bool {
+        self.until.map_or(true, |until| chrono::Utc::now() < until)
+    }
+}
+
+/// Fleet-wide control document (`<remote>.control.json`)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct Control {
+    /// Pause all followers of the remote
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paused: Option<RemotePause>,
+    /// Pause followers of given configurations
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub paused_configurations: BTreeMap<String, RemotePause>,
+    /// Keep followers of given configurations on a given remote etag
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pinned: BTreeMap<String, String>,
+}
+
+impl Control {
+    /// Missing control document means no control
+    ///
+    /// Any other failure (including access denied) is an error, so a
+    /// misconfigured host does not silently ignore a fleet pause.
+    pub fn fetch(remote: &Url, region: Option<&str>) -> anyhow::Result<Self> {
+        Ok(store::get_json(&control_url(remote), region)?.unwrap_or_default())
+    }
+
+    /// Like [`Self::fetch`], along with the etag of the control document
+    /// (`None` if it does not exist yet)
+    pub fn fetch_with_etag(
+        remote: &Url,
+        region: Option<&str>,
+    ) -> anyhow::Result<(Self, Option<String>)> {
+        let url = control_url(remote);
+        match store::get_object_with_etag(&url, region)? {
+            Some((content, etag)) => Ok((
+                serde_json::from_slice(&content)
+                    .with_context(|| format!("Failed to parse {url}"))?,
+                Some(etag),
+            )),
+            None => Ok((Self::default(), None)),
+        }
+    }
+
+    /// Store the control document, unless it was changed since it was
+    /// fetched with `etag`
+    ///
+    /// Returns `false` on conflict.
+    pub fn store_if_unchanged(
+        &self,
+        remote: &Url,
+        etag: Option<&str>,
+        region: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let url = control_url(remote);
+        let content = serde_json::to_vec_pretty(self)?;
+        match etag {
+            Some(etag) => store::put_object_if_match(&url, &content, etag, region),
+            None => store::put_object_if_absent(&url, &content, region),
+        }
+    }
+
+    /// Active pause applying to `configuration`, if any
+    pub fn pause_for(&self, configuration: &str) -> Option<&RemotePause> {
+        self.paused.as_ref().filter(|p| p.is_active()).or_else(|| {
+            self.paused_configurations
+                .get(configuration)
+                .filter(|p| p.is_active())
+        })
+    }
+
+    pub fn pinned_etag(&self, configuration: &str) -> Option<&str> {
+        self.pinned.get(configuration).map(String::as_str)
+    }
+
+    pub fn with_paused(mut self, configuration: Option<&str>, pause: RemotePause) -> Self {
+        match configuration {
+            Some(configuration) => {
+                self.paused_configurations
+                    .insert(configuration.to_owned(), pause);
+            }
+            None => self.paused = Some(pause),
+        }
+        self
+    }
+
+    pub fn with_unpaused(mut self, configuration: Option<&str>) -> Self {
+        match configuration {
+            Some(configuration) => {
+                self.paused_configurations.remove(configuration);
+            }
+            None => self.paused = None,
+        }
+        self
+    }
+
+    pub fn with_pinned(mut self, configuration: &str, etag: Option<&str>) -> Self {
+        match etag {
+            Some(etag) => {
+                self.pinned
+                    .insert(configuration.to_owned(), etag.to_owned());
+            }
+            None => {
+                self.pinned.remove(configuration);
+            }
+        }
+        self
+    }
+
+    /// Remove expired pauses
+    pub fn expire_paused(mut self) -> Self {
+        self.paused = self.paused.filter(RemotePause::is_active);
+        self.paused_configurations.retain(|_, p| p.is_active());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fetch_missing() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let remote = Url::from_file_path(dir.path().join("main")).unwrap();
+        let control = Control::fetch(&remote, None).unwrap();
+        assert!(control.paused.is_none());
+        assert!(control.pause_for("a").is_none());
+        assert!(control.pinned_etag("a").is_none());
+    }
+
+    #[test]
+    fn store_if_unchanged() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let remote = Url::from_file_path(dir.path().join("main")).unwrap();
+
+        let (control, etag) = Control::fetch_with_etag(&remote, None).unwrap();
+        assert!(etag.is_none());
+        let control = control.with_pinned("a", Some("etag-a"));
+        assert!(control.store_if_unchanged(&remote, None, None).unwrap());
+
+        // A concurrent update in between loses nothing
+        let (first, first_etag) = Control::fetch_with_etag(&remote, None).unwrap();
+        let (second, second_etag) = Control::fetch_with_etag(&remote, None).unwrap();
+        assert!(second
+            .with_pinned("b", Some("etag-b"))
+            .store_if_unchanged(&remote, second_etag.as_deref(), None)
+            .unwrap());
+        assert!(!first
+            .with_unpaused(None)
+            .store_if_unchanged(&remote, first_etag.as_deref(), None)
+            .unwrap());
+
+        let control = Control::fetch(&remote, None).unwrap();
+        assert_eq!(control.pinned_etag("a"), Some("etag-a"));
+        assert_eq!(control.pinned_etag("b"), Some("etag-b"));
+    }
+}
@@ -0,0 +1,457 @@
+use std::io::{BufRead, Read, Write};
+use std::os::unix::fs::MetadataExt as _;
+use std::os::unix::io::AsRawFd as _;
+use std::os::unix::process::CommandExt as _;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitStatus, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use std::{fmt, fs, io, thread};
+
+use anyhow::{bail, format_err, Context};
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGHUP, SIGUSR1, TERM_SIGNALS};
+use signal_hook::iterator::Signals;
+use tracing::{debug, info, warn};
+
+use crate::CommandExt as _;
+
+/// Signal making a running `npcnix follow` check the remote immediately
+pub const TRIGGER_SIGNAL: i32 = SIGUSR1;
+
+#[derive(Debug, Default)]
+struct WakeState {
+    shutdown: bool,
+    trigger: bool,
+}
+
+/// Signal handling of the follow loop
+///
+/// Termination signals request a clean shutdown: an in-progress activation
+/// is allowed to finish, while a sleep is interrupted. A repeated termination
+/// signal exits immediately. `SIGHUP`/`SIGUSR1` interrupt the sleep to check
+/// the remote right away.
+#[derive(Debug, Clone)]
+pub struct Wake {
+    inner: Arc<(Mutex<WakeState>, Condvar)>,
+}
+
+impl Wake {
+    pub fn install() -> anyhow::Result<Self> {
+        let wake = Self::new();
+        let mut signals = Signals::new(TERM_SIGNALS.iter().chain(&[SIGHUP, SIGUSR1]))?;
+        thread::spawn({
+            let wake = wake.clone();
+            move || {
+                for signal in signals.forever() {
+                    if TERM_SIGNALS.contains(&signal) {
+                        if wake.shutdown_requested() {
+                            warn!(signal, "Repeated termination signal; exiting immediately");
+                            std::process::exit(128 + signal);
+                        }
+                        info!(signal, "Shutdown requested");
+                        wake.update(|state| state.shutdown = true);
+                    } else {
+                        debug!(signal, "Check triggered");
+                        wake.trigger();
+                    }
+                }
+            }
+        });
+        Ok(wake)
+    }
+
+    /// Without handling any signals
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Arc::new((Mutex::new(WakeState::default()), Condvar::new())),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut WakeState)) {
+        let (lock, cond) = &*self.inner;
+        f(&mut lock.lock().expect("not poisoned"));
+        cond.notify_all();
+    }
+
+    /// Interrupt the current (or next) sleep to check the remote
+    pub fn trigger(&self) {
+        self.update(|state| state.trigger = true);
+    }
+
+    pub fn shutdown_requested(&self) -> bool {
+        self.inner.0.lock().expect("not poisoned").shutdown
+    }
+
+    /// Sleep for `duration`, unless a shutdown or a check is requested
+    /// earlier (including while not sleeping)
+    pub fn sleep(&self, duration: Duration) {
+        let (lock, cond) = &*self.inner;
+        let deadline = Instant::now() + duration;
+        let mut state = lock.lock().expect("not poisoned");
+        while !state.shutdown && !state.trigger {
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            state = cond.wait_timeout(state, timeout).expect("not poisoned").0;
+        }
+        if state.trigger {
+            info!("Woken up to check the remote");
+        }
+        state.trigger = false;
+    }
+}
+
+/// Pid file of a running `npcnix follow`; removed on drop
+///
+/// Kept open with an exclusive `flock` for the lifetime of the process, so
+/// only one `npcnix follow` can own it at a time.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    _file: fs::File,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        loop {
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(path)
+                .with_context(|| format!("Failed to open pid file: {}", path.display()))?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    let pid = fs::read_to_string(path).unwrap_or_default();
+                    bail!("npcnix follow is already running (pid {})", pid.trim());
+                }
+                return Err(e)
+                    .with_context(|| format!("Failed to lock pid file: {}", path.display()));
+            }
+            // The previous owner might have removed the file after we opened
+            // it; in that case lock the new one instead
+            let same_file = match fs::metadata(path) {
+                Ok(metadata) => metadata.ino() == file.metadata()?.ino(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+                Err(e) => return Err(e.into()),
+            };
+            if !same_file {
+                continue;
+            }
+            file.set_len(0)?;
+            writeln!(file, "{}", std::process::id())
+                .with_context(|| format!("Failed to write pid file: {}", path.display()))?;
+            return Ok(Self {
+                path: path.to_owned(),
+                _file: file,
+            });
+        }
+    }
+
+    /// Pid of the running daemon, if any
+    pub fn read(path: &Path) -> anyhow::Result<Option<i32>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let pid = content
+            .trim()
+            .parse::<i32>()
+            .with_context(|| format!("Invalid pid file: {}", path.display()))?;
+        // Signal `0` only checks if the process exists
+        Ok((unsafe { libc::kill(pid, 0) } == 0).then_some(pid))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), error = %e, "Failed to remove pid file");
+        }
+    }
+}
+
+/// Make the daemon with a pid file at `pid_path` check the remote immediately
+pub fn trigger(pid_path: &Path) -> anyhow::Result<()> {
+    let Some(pid) = PidFile::read(pid_path)? else {
+        bail!("npcnix follow is not running (no pid file or stale pid file)");
+    };
+    if unsafe { libc::kill(pid, TRIGGER_SIGNAL) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to signal npcnix (pid {pid})"));
+    }
+    debug!(pid, "Triggered check");
+    Ok(())
+}
+
+/// What the daemon is currently doing
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Phase {
+    #[default]
+    Idle,
+    Sleeping {
+        until: chrono::DateTime<chrono::Utc>,
+    },
+    /// Checking the remote for changes
+    Checking,
+    /// Downloading the remote
+    Fetching,
+    /// Evaluating the configuration (observe-only mode)
+    Evaluating,
+    /// Building the configuration
+    Building,
+    /// Switching the system to the new configuration; can't be aborted
+    Switching,
+    /// Publishing the status report
+    Reporting,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Idle => f.write_str("idle"),
+            Self::Sleeping { until } => write!(
+                f,
+                "sleeping until {}",
+                until.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            ),
+            Self::Checking => f.write_str("checking"),
+            Self::Fetching => f.write_str("fetching"),
+            Self::Evaluating => f.write_str("evaluating"),
+            Self::Building => f.write_str("building"),
+            Self::Switching => f.write_str("switching"),
+            Self::Reporting => f.write_str("reporting"),
+        }
+    }
+}
+
+impl Phase {
+    fn is_abortable(&self) -> bool {
+        matches!(self, Self::Fetching | Self::Evaluating | Self::Building)
+    }
+}
+
+/// Runtime status of the daemon, as served over the control socket
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub phase: Phase,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase_since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Last line of output of the current step
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<String>,
+    /// Etag of the remote seen during the last check
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+}
+
+#[derive(Debug)]
+struct Runtime {
+    phase: Phase,
+    phase_since: Option<chrono::DateTime<chrono::Utc>>,
+    progress: Option<String>,
+    etag: Option<String>,
+    /// Process group of the running command, if abortable
+    child: Option<i32>,
+    aborted: bool,
+}
+
+static RUNTIME: Mutex<Runtime> = Mutex::new(Runtime {
+    phase: Phase::Idle,
+    phase_since: None,
+    progress: None,
+    etag: None,
+    child: None,
+    aborted: false,
+});
+
+fn with_runtime<T>(f: impl FnOnce(&mut Runtime) -> T) -> T {
+    f(&mut RUNTIME.lock().expect("not poisoned"))
+}
+
+pub fn set_phase(phase: Phase) {
+    crate::systemd::heartbeat();
+    let changed = with_runtime(|runtime| {
+        let changed = runtime.phase != phase;
+        if changed {
+            runtime.phase = phase;
+            runtime.phase_since = Some(chrono::Utc::now());
+            runtime.progress = None;
+        }
+        changed
+    });
+    if changed {
+        notify_status();
+    }
+}
+
+/// Update the progress of the current phase (e.g. the last line of output)
+pub fn set_progress(progress: &str) {
+    with_runtime(|runtime| runtime.progress = Some(progress.to_owned()));
+}
+
+pub fn set_etag(etag: &str) {
+    with_runtime(|runtime| runtime.etag = Some(etag.to_owned()));
+    notify_status();
+}
+
+/// Update the status text shown by `systemctl status`
+fn notify_status() {
+    let status = with_runtime(|runtime| match runtime.etag {
+        Some(ref etag) => format!("STATUS={} (remote etag {etag})", runtime.phase),
+        None => format!("STATUS={}", runtime.phase),
+    });
+    crate::systemd::notify(&status);
+}
+
+pub fn status() -> DaemonStatus {
+    with_runtime(|runtime| DaemonStatus {
+        pid: process::id(),
+        phase: runtime.phase.clone(),
+        phase_since: runtime.phase_since,
+        progress: runtime.progress.clone(),
+        etag: runtime.etag.clone(),
+    })
+}
+
+/// Abort the currently running fetch/build
+pub fn abort() -> anyhow::Result<()> {
+    with_runtime(|runtime| {
+        if !runtime.phase.is_abortable() {
+            bail!("Nothing to abort ({})", runtime.phase);
+        }
+        let Some(pgid) = runtime.child else {
+            bail!("Nothing to abort");
+        };
+        info!(pgid, "Aborting");
+        if unsafe { libc::kill(-pgid, libc::SIGTERM) } != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to abort");
+        }
+        runtime.aborted = true;
+        Ok(())
+    })
+}
+
+/// Run `cmd` tracking the last line of its stderr as the progress
+///
+/// The command runs in its own process group, so it can be aborted, and a
+/// terminal's Ctrl+C doesn't interrupt a switch half-way (npcnix itself
+/// waits for it to finish before shutting down).
+/// Returns the captured stdout, if `capture_stdout` is set; otherwise the
+/// output is also copied to `log`.
+pub fn run_tracked(
+    cmd: &mut process::Command,
+    capture_stdout: bool,
+    log: Option<&fs::File>,
+) -> anyhow::Result<(ExitStatus, Vec<u8>)> {
+    cmd.process_group(0).stderr(Stdio::piped());
+    if capture_stdout || log.is_some() {
+        cmd.stdout(Stdio::piped());
+    }
+    let mut child = cmd.log_debug().spawn()?;
+    let stderr = child.stderr.take().expect("piped");
+    with_runtime(|runtime| {
+        runtime.child = i32::try_from(child.id()).ok();
+        runtime.aborted = false;
+    });
+
+    let log = log.map(fs::File::try_clone).transpose()?;
+    let stdout_thread = match (capture_stdout, child.stdout.take(), &log) {
+        (false, Some(stdout), Some(log)) => {
+            let log = log.try_clone()?;
+            Some(thread::spawn(move || {
+                tee(stdout, io::stdout(), log, |_| {})
+            }))
+        }
+        (_, stdout, _) => {
+            child.stdout = stdout;
+            None
+        }
+    };
+    let stderr_thread = thread::spawn(move || {
+        let on_line = |line: &str| {
+            set_progress(line);
+        };
+        match log {
+            Some(log) => tee(stderr, io::stderr(), log, on_line),
+            None => tee(stderr, io::stderr(), io::sink(), on_line),
+        }
+    });
+
+    let mut stdout = vec![];
+    if let Some(ref mut child_stdout) = child.stdout {
+        child_stdout.read_to_end(&mut stdout)?;
+    }
+    let status = child.wait();
+    let _ = stderr_thread.join();
+    if let Some(stdout_thread) = stdout_thread {
+        let _ = stdout_thread.join();
+    }
+    let aborted = with_runtime(|runtime| {
+        runtime.child = None;
+        std::mem::take(&mut runtime.aborted)
+    });
+    if aborted {
+        return Err(format_err!("Aborted on request"));
+    }
+    Ok((status?, stdout))
+}
+
+/// Copy lines from `src` to both `out` and `log`
+///
+/// Keeps reading until EOF, even if the output is not valid UTF-8, so the
+/// child never blocks on a full pipe.
+fn tee(src: impl Read, mut out: impl Write, mut log: impl Write, on_line: impl Fn(&str)) {
+    let mut src = io::BufReader::new(src);
+    let mut buf = vec![];
+    loop {
+        buf.clear();
+        match src.read_until(b'\n', &mut buf) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches(['\n', '\r']);
+        let _ = writeln!(out, "{line}");
+        let _ = writeln!(log, "{line}");
+        on_line(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_file_exclusive() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("npcnix.pid");
+        let pid_file = PidFile::create(&path).unwrap();
+        assert!(PidFile::create(&path).is_err());
+        assert_eq!(
+            PidFile::read(&path).unwrap(),
+            Some(i32::try_from(std::process::id()).unwrap())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+        let _pid_file = PidFile::create(&path).unwrap();
+    }
+
+    #[test]
+    fn tee_non_utf8() {
+        let mut out = vec![];
+        let mut log = vec![];
+        tee(&b"a\n\xff\nb"[..], &mut out, &mut log, |_| {});
+        assert_eq!(out, "a\n\u{fffd}\nb\n".as_bytes());
+        assert_eq!(out, log);
+    }
+}
@@ -2,19 +2,40 @@ use std::fs;
 use std::path::{Path, PathBuf};
 
 use anyhow::Context;
+use tracing::warn;
 use url::Url;
 
 use crate::config;
+use crate::observe::Observation;
+use crate::state::State;
+
+/// Default locations of the declarative config, checked in order
+const DEFAULT_DECLARATIVE_CONFIG_PATHS: &[&str] =
+    &["/etc/npcnix/config.toml", "/etc/npcnix/config.json"];
 
 #[derive(Debug, Clone)]
 pub struct DataDir {
     path: PathBuf,
+    declarative_config_path: Option<PathBuf>,
 }
 
 impl DataDir {
     pub fn new(path: &Path) -> Self {
         Self {
             path: path.to_owned(),
+            declarative_config_path: DEFAULT_DECLARATIVE_CONFIG_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .find(|path| path.exists()),
+        }
+    }
+
+    /// Use a given declarative (read-only) config file instead of the default
+    /// one
+    pub fn with_declarative_config_path(self, path: Option<&Path>) -> Self {
+        Self {
+            declarative_config_path: path.map(ToOwned::to_owned),
+            ..self
         }
     }
 
@@ -28,6 +49,25 @@ impl DataDir {
         }
     }
 
+    /// Run `f` while holding an exclusive lock on `config.lock`
+    ///
+    /// Separate from `activate.lock`, so it is only held for the short duration
+    /// of read-modify-write of the config and state files.
+    fn with_config_lock<T>(&self, f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        fs::create_dir_all(&self.path)
+            .with_context(|| format!("Failed to create data directory: {}", self.path.display()))?;
+        let mut lock = fd_lock::RwLock::new(
+            fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(self.path.join("config.lock"))
+                .context("Failed to open config lock file")?,
+        );
+        let _guard = lock.write().context("Failed to lock config")?;
+        f()
+    }
+
     /// Load currently configured `remote` from config if not overridden
     pub fn get_current_remote_with_opt_override(
         &self,
@@ -39,16 +79,18 @@ impl DataDir {
             .or_else(|_| -> anyhow::Result<Url> { Ok(self.load_config()?.remote()?.clone()) })
     }
 
-    /// Load currently configured `configuration` if not overridden
+    /// Select the configuration to use (see
+    /// [`crate::host::select_configuration`]) if not overridden
     pub fn get_current_configuration_with_opt_override(
         &self,
         configuration: Option<&str>,
+        src: Option<&Path>,
     ) -> anyhow::Result<String> {
         configuration
             .map(ToOwned::to_owned)
             .ok_or(())
             .or_else(|_| -> anyhow::Result<String> {
-                Ok(self.load_config()?.configuration()?.to_owned())
+                crate::host::select_configuration(&self.load_config()?, src)
             })
     }
 
@@ -57,10 +99,32 @@ impl DataDir {
     }
 
     pub fn config_exist(&self) -> anyhow::Result<bool> {
-        Ok(self.config_file_path().try_exists()?)
+        Ok(self.config_file_path().try_exists()?
+            || self
+                .declarative_config_path
+                .as_deref()
+                .map(Path::try_exists)
+                .transpose()?
+                .unwrap_or(false))
     }
 
+    /// Load the effective config: declarative config layered over the runtime
+    /// one
     pub fn load_config(&self) -> anyhow::Result<config::Config> {
+        Ok(self.load_layered_config()?.into_config())
+    }
+
+    pub fn load_layered_config(&self) -> anyhow::Result<config::LayeredConfig> {
+        let config_path = self.config_file_path();
+        config::LayeredConfig::load(
+            config_path.exists().then_some(config_path.as_path()),
+            self.declarative_config_path.as_deref(),
+        )
+        .context("Failed to load config")
+    }
+
+    /// Load only the runtime (mutable) config
+    fn load_runtime_config(&self) -> anyhow::Result<config::Config> {
         let config_path = self.config_file_path();
         if config_path.exists() {
             config::Config::load(&self.config_file_path()).context("Failed to load config")
@@ -69,7 +133,39 @@ impl DataDir {
         }
     }
 
-    pub fn store_config(&self, config: &config::Config) -> anyhow::Result<()> {
+    /// Atomically modify the runtime config
+    ///
+    /// Settings that are also set in the declarative config will be stored,
+    /// but will have no effect.
+    pub fn update_config(
+        &self,
+        f: impl FnOnce(config::Config) -> anyhow::Result<config::Config>,
+    ) -> anyhow::Result<()> {
+        self.with_config_lock(|| {
+            let prev = self.load_runtime_config()?;
+            let new = f(prev.clone())?;
+            // Rewriting the config drops legacy state fields, so save them first
+            self.migrate_legacy_state()?;
+            self.store_config(&new)?;
+
+            let (serde_json::Value::Object(prev), serde_json::Value::Object(new)) =
+                (serde_json::to_value(prev)?, serde_json::to_value(new)?)
+            else {
+                return Ok(());
+            };
+            for key in self.load_layered_config()?.declarative_keys() {
+                if prev.get(key) != new.get(key) {
+                    warn!(
+                        key,
+                        "Setting is overridden by the declarative config and will have no effect"
+                    );
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn store_config(&self, config: &config::Config) -> anyhow::Result<()> {
         fs::create_dir_all(&self.path)
             .with_context(|| format!("Failed to create data directory: {}", self.path.display()))?;
         config
@@ -77,15 +173,149 @@ impl DataDir {
             .context("Failed to store config")
     }
 
+    /// Pid file of the running `npcnix follow`
+    pub fn pid_file_path(&self) -> PathBuf {
+        self.path.join("npcnix.pid")
+    }
+
+    /// Control socket of the running `npcnix follow`
+    pub fn socket_path(&self) -> PathBuf {
+        self.path.join("npcnix.sock")
+    }
+
+    /// Directory with activation records and logs
+    pub fn history_dir(&self) -> PathBuf {
+        self.path.join("history")
+    }
+
+    /// Directory with cached unpacked remote versions
+    pub fn sources_dir(&self) -> PathBuf {
+        self.path.join("sources")
+    }
+
+    /// Nix GC root keeping the source of the active generation in the store
+    pub fn source_gc_root_path(&self) -> PathBuf {
+        self.path.join("active-source")
+    }
+
+    /// Metrics counters, kept across daemon restarts
+    pub fn metrics_counters_path(&self) -> PathBuf {
+        self.path.join("metrics.json")
+    }
+
+    fn state_file_path(&self) -> PathBuf {
+        self.path.join("state.json")
+    }
+
+    /// Load the runtime state
+    ///
+    /// If there is no `state.json` yet, the state is migrated from
+    /// `config.json` of older versions.
+    pub fn load_state(&self) -> anyhow::Result<State> {
+        let state_path = self.state_file_path();
+        if state_path.exists() {
+            State::load(&state_path).context("Failed to load state")
+        } else {
+            Ok(State::load_legacy(&self.config_file_path())
+                .context("Failed to load legacy state from config")?
+                .unwrap_or_default())
+        }
+    }
+
+    /// Write `state.json` from the legacy state fields of the config, if not
+    /// done already; must be called with the config lock held
+    fn migrate_legacy_state(&self) -> anyhow::Result<()> {
+        if self.state_file_path().exists() {
+            return Ok(());
+        }
+        if let Some(state) = State::load_legacy(&self.config_file_path())
+            .context("Failed to load legacy state from config")?
+        {
+            self.store_state(&state)?;
+        }
+        Ok(())
+    }
+
+    /// Atomically modify the state
+    pub fn update_state(&self, f: impl FnOnce(State) -> State) -> anyhow::Result<()> {
+        self.with_config_lock(|| self.store_state(&f(self.load_state()?)))
+    }
+
+    fn store_state(&self, state: &State) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.path)
+            .with_context(|| format!("Failed to create data directory: {}", self.path.display()))?;
+        state
+            .store(&self.state_file_path())
+            .context("Failed to store state")
+    }
+
     pub fn update_last_reconfiguration(
         &self,
         configuration: &str,
         etag: &str,
     ) -> anyhow::Result<()> {
-        self.store_config(
-            &self
-                .load_config()?
-                .with_updated_last_reconfiguration(configuration, etag),
+        let current_system = crate::drift::current_system()
+            .map_err(|e| warn!(error = %e, "Failed to resolve current system"))
+            .ok()
+            .flatten();
+        let current_generation = crate::system_profile_generation()
+            .map_err(|e| warn!(error = %e, "Failed to read system profile generation"))
+            .ok()
+            .flatten();
+        self.update_state(|state| {
+            state.with_updated_last_reconfiguration(
+                configuration,
+                etag,
+                current_generation,
+                current_system,
+            )
+        })
+    }
+
+    pub fn record_activation_failure(
+        &self,
+        configuration: &str,
+        etag: &str,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        self.update_state(|state| state.with_activation_failure(configuration, etag, error))
+    }
+
+    pub fn record_error(&self, error: &str) -> anyhow::Result<()> {
+        self.update_state(|state| state.with_error(error))
+    }
+
+    pub fn record_observation(&self, observation: Observation) -> anyhow::Result<()> {
+        self.update_state(|state| state.with_observation(observation))
+    }
+
+    pub fn record_check_succeeded(
+        &self,
+        remote_etag: &str,
+        reset_failures: bool,
+    ) -> anyhow::Result<()> {
+        self.update_state(|state| state.with_check_succeeded(remote_etag, reset_failures))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_state_survives_config_update() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let data_dir = DataDir::new(dir.path());
+        fs::write(
+            data_dir.config_file_path(),
+            r#"{"last_etag": "abc", "last_configuration": "host"}"#,
         )
+        .unwrap();
+
+        data_dir.update_config(Ok).unwrap();
+
+        let state = data_dir.load_state().unwrap();
+        assert_eq!(state.last_etag(), "abc");
+        assert_eq!(state.last_configuration(), "host");
     }
 }
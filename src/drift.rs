@@ -0,0 +1,57 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::State;
+use crate::{current_system_path, system_profile_path};
+
+/// What to do when the running system differs from the one npcnix activated
+/// (e.g. after a manual `nixos-rebuild switch`)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftPolicy {
+    /// Only report the drift
+    #[default]
+    ReportOnly,
+    /// Re-activate the remote configuration
+    Reconverge,
+}
+
+/// Detected difference between the system activated by npcnix and the
+/// current one
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Drift {
+    pub expected: PathBuf,
+    pub actual: PathBuf,
+}
+
+fn resolve(path: &Path) -> anyhow::Result<Option<PathBuf>> {
+    match path.canonicalize() {
+        Ok(path) => Ok(Some(path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Resolved store path of the currently running system
+pub fn current_system() -> anyhow::Result<Option<PathBuf>> {
+    resolve(&current_system_path())
+}
+
+/// Resolved store path of the system profile, i.e. the system the host
+/// boots into by default
+pub fn profile_system() -> anyhow::Result<Option<PathBuf>> {
+    resolve(&system_profile_path())
+}
+
+/// Compare the system recorded at the last activation with the current one
+pub fn detect(state: &State) -> anyhow::Result<Option<Drift>> {
+    let Some(expected) = state.current_system() else {
+        return Ok(None);
+    };
+    let actual = current_system()?.unwrap_or_default();
+    Ok((expected != actual).then(|| Drift {
+        expected: expected.to_owned(),
+        actual,
+    }))
+}
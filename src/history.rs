@@ -0,0 +1,376 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use anyhow::{format_err, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::data_dir::DataDir;
+use crate::plan::Plan;
+
+/// What started an activation
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivationMode {
+    /// `npcnix follow`
+    Follow,
+    /// `npcnix activate`
+    Manual,
+    /// `npcnix rollback`
+    Rollback,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Still running (or npcnix died in the middle of it)
+    InProgress,
+    Success,
+    Failure,
+}
+
+/// Record of a single activation attempt (`history/<id>.json` in the data
+/// dir), with its output in `history/<id>.log`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ActivationRecord {
+    pub id: String,
+    pub start: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<chrono::DateTime<chrono::Utc>>,
+    pub configuration: String,
+    /// Remote etag; not set for manual activations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    pub mode: ActivationMode,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Store path of the system after a successful activation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<PathBuf>,
+    /// Changes expected before switching, if planning was enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan: Option<Plan>,
+}
+
+impl ActivationRecord {
+    pub fn duration(&self) -> Option<chrono::Duration> {
+        self.end.map(|end| end - self.start)
+    }
+}
+
+/// An activation attempt in progress; see [`Entry::finish`]
+///
+/// Recording the history is best-effort: failures to write it are logged,
+/// but never fail the activation itself.
+#[derive(Debug)]
+pub struct Entry {
+    dir: PathBuf,
+    record: ActivationRecord,
+    log: fs::File,
+    max_history: usize,
+}
+
+impl Entry {
+    /// Start recording an activation; `None` if the history can't be written
+    pub fn start(
+        data_dir: &DataDir,
+        mode: ActivationMode,
+        configuration: &str,
+        etag: Option<&str>,
+    ) -> Option<Self> {
+        Self::try_start(data_dir, mode, configuration, etag)
+            .map_err(|e| warn!(error = %e, "Failed to start activation record"))
+            .ok()
+    }
+
+    fn try_start(
+        data_dir: &DataDir,
+        mode: ActivationMode,
+        configuration: &str,
+        etag: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let dir = data_dir.history_dir();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create history dir: {}", dir.display()))?;
+        let start = chrono::Utc::now();
+        let base_id = start.format("%Y%m%d-%H%M%S").to_string();
+        let (id, log) = (0..)
+            .map(|n| match n {
+                0 => base_id.clone(),
+                n => format!("{base_id}-{n}"),
+            })
+            .find_map(|id| {
+                match fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(log_path(&dir, &id))
+                {
+                    Ok(log) => Some(Ok((id, log))),
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => None,
+                    Err(e) => Some(Err(e)),
+                }
+            })
+            .expect("infinite iterator")
+            .context("Failed to create activation log")?;
+        let entry = Self {
+            dir,
+            record: ActivationRecord {
+                id,
+                start,
+                end: None,
+                configuration: configuration.to_owned(),
+                etag: etag.map(ToOwned::to_owned),
+                mode,
+                outcome: Outcome::InProgress,
+                exit_code: None,
+                error: None,
+                system: None,
+                plan: None,
+            },
+            log,
+            max_history: data_dir.load_config()?.max_history(),
+        };
+        entry.store()?;
+        debug!(id = entry.record.id, "Started activation record");
+        Ok(entry)
+    }
+
+    /// Log file capturing the activation output
+    pub fn log(&self) -> &fs::File {
+        &self.log
+    }
+
+    pub fn set_exit_status(&mut self, status: ExitStatus) {
+        self.record.exit_code = status.code();
+    }
+
+    pub fn set_plan(&mut self, plan: Plan) {
+        self.record.plan = Some(plan);
+        if let Err(e) = self.store() {
+            warn!(error = %e, "Failed to store activation record");
+        }
+    }
+
+    fn store(&self) -> anyhow::Result<()> {
+        crate::misc::store_json_pretty_to_file(
+            &record_path(&self.dir, &self.record.id),
+            &self.record,
+        )
+    }
+
+    /// Record the outcome and rotate old records
+    pub fn finish<T>(mut self, res: &anyhow::Result<T>) {
+        self.record.end = Some(chrono::Utc::now());
+        match res {
+            Ok(_) => {
+                self.record.outcome = Outcome::Success;
+                // The activation itself succeeded; don't turn it into a failure
+                self.record.system = crate::drift::current_system()
+                    .map_err(|e| warn!(error = %e, "Failed to resolve current system"))
+                    .ok()
+                    .flatten();
+            }
+            Err(e) => {
+                self.record.outcome = Outcome::Failure;
+                self.record.error = Some(format!("{e:#}"));
+            }
+        }
+        if let Err(e) = self.store() {
+            warn!(error = %e, "Failed to store activation record");
+        }
+        if let Err(e) = rotate(&self.dir, self.max_history) {
+            warn!(error = %e, "Failed to rotate activation history");
+        }
+    }
+}
+
+fn record_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.json"))
+}
+
+/// Whether `id` has the format of generated activation ids
+/// (`YYYYmmdd-HHMMSS`, optionally followed by `-N`)
+fn is_valid_id(id: &str) -> bool {
+    let all_digits = |s: &str, len: Option<usize>| {
+        !s.is_empty()
+            && len.map_or(true, |len| s.len() == len)
+            && s.bytes().all(|b| b.is_ascii_digit())
+    };
+    let mut parts = id.split('-');
+    matches!(
+        (parts.next(), parts.next(), parts.next(), parts.next()),
+        (Some(date), Some(time), suffix, None)
+            if all_digits(date, Some(8))
+                && all_digits(time, Some(6))
+                && suffix.map_or(true, |suffix| all_digits(suffix, None))
+    )
+}
+
+fn log_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.log"))
+}
+
+/// All activation records, oldest first
+pub fn list(data_dir: &DataDir) -> anyhow::Result<Vec<ActivationRecord>> {
+    let dir = data_dir.history_dir();
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        match fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(serde_json::from_slice::<ActivationRecord>(&content)?))
+        {
+            Ok(record) => records.push(record),
+            Err(e) => warn!(path = %path.display(), error = %e, "Invalid activation record"),
+        }
+    }
+    records.sort_by(|a, b| (a.start, &a.id).cmp(&(b.start, &b.id)));
+    Ok(records)
+}
+
+/// Path of the log of activation `id` (`last` for the latest one)
+pub fn log_file(data_dir: &DataDir, id: &str) -> anyhow::Result<PathBuf> {
+    let id = match id {
+        "last" => {
+            list(data_dir)?
+                .pop()
+                .ok_or_else(|| format_err!("No activations recorded yet"))?
+                .id
+        }
+        id if is_valid_id(id) => id.to_owned(),
+        id => anyhow::bail!("Invalid activation id: {id}"),
+    };
+    let path = log_path(&data_dir.history_dir(), &id);
+    if !path.exists() {
+        anyhow::bail!("No log of activation {id}");
+    }
+    Ok(path)
+}
+
+fn rotate(dir: &Path, max_history: usize) -> anyhow::Result<()> {
+    let mut ids: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            (path.extension()? == "json")
+                .then(|| path.file_stem()?.to_str().map(ToOwned::to_owned))?
+        })
+        .collect();
+    ids.sort();
+    let excess = ids.len().saturating_sub(max_history);
+    for id in &ids[..excess] {
+        debug!(id, "Removing old activation record");
+        for path in [record_path(dir, id), log_path(dir, id)] {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Table of activation records
+pub struct History(pub Vec<ActivationRecord>);
+
+impl fmt::Display for History {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<20} {:<20} {:<8} {:<20} {:<36} {:<11} {:>8} SYSTEM",
+            "ID", "START", "MODE", "CONFIGURATION", "ETAG", "OUTCOME", "DURATION"
+        )?;
+        for record in &self.0 {
+            writeln!(
+                f,
+                "{:<20} {:<20} {:<8} {:<20} {:<36} {:<11} {:>8} {}",
+                record.id,
+                record
+                    .start
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                match record.mode {
+                    ActivationMode::Follow => "follow",
+                    ActivationMode::Manual => "manual",
+                    ActivationMode::Rollback => "rollback",
+                },
+                record.configuration,
+                record.etag.as_deref().unwrap_or("-"),
+                match record.outcome {
+                    Outcome::InProgress => "in-progress",
+                    Outcome::Success => "success",
+                    Outcome::Failure => "failure",
+                },
+                record
+                    .duration()
+                    .map(|duration| format!("{}s", duration.num_seconds()))
+                    .unwrap_or_default(),
+                record
+                    .system
+                    .as_deref()
+                    .map(|system| system.display().to_string())
+                    .unwrap_or_default(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_ids() {
+        for id in ["20261018-093000", "20261018-093000-1", "20261018-093000-12"] {
+            assert!(is_valid_id(id), "{id}");
+        }
+        for id in [
+            "",
+            "last",
+            "20261018",
+            "20261018-0930",
+            "20261018-093000-",
+            "20261018-093000-1-2",
+            "../../etc/passwd",
+            "20261018-093000/../x",
+        ] {
+            assert!(!is_valid_id(id), "{id}");
+        }
+    }
+
+    #[test]
+    fn unwritable_history() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let data_dir = DataDir::new(dir.path());
+        fs::write(data_dir.history_dir(), "not a directory").unwrap();
+        assert!(Entry::start(&data_dir, ActivationMode::Manual, "host", None).is_none());
+    }
+
+    #[test]
+    fn finish_records_outcome() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let data_dir = DataDir::new(dir.path());
+        let entry = Entry::start(&data_dir, ActivationMode::Manual, "host", None).unwrap();
+        entry.finish(&Err::<(), _>(format_err!("boom")));
+
+        let records = list(&data_dir).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].outcome, Outcome::Failure);
+        assert_eq!(records[0].error.as_deref(), Some("boom"));
+    }
+}
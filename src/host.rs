@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io};
+
+use anyhow::{bail, format_err, Context};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+fn hostname_path() -> PathBuf {
+    std::env::var_os("NPCNIX_HOSTNAME_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/proc/sys/kernel/hostname"))
+}
+
+fn machine_id_path() -> PathBuf {
+    std::env::var_os("NPCNIX_MACHINE_ID_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/etc/machine-id"))
+}
+
+fn cloud_init_instance_id_path() -> PathBuf {
+    std::env::var_os("NPCNIX_INSTANCE_ID_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/var/lib/cloud/data/instance-id"))
+}
+
+const EC2_IMDS_URL: &str = "http://169.254.169.254/latest";
+
+fn read_trimmed_opt(path: &Path) -> anyhow::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(Some(s.trim().to_owned()).filter(|s| !s.is_empty())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+pub fn hostname() -> anyhow::Result<String> {
+    read_trimmed_opt(&hostname_path())?.ok_or_else(|| format_err!("Hostname not available"))
+}
+
+pub fn machine_id() -> anyhow::Result<String> {
+    read_trimmed_opt(&machine_id_path())?.ok_or_else(|| format_err!("Machine id not available"))
+}
+
+/// Stable identity of this host: machine id, or hostname if not available
+pub fn host_id() -> anyhow::Result<String> {
+    machine_id().or_else(|_| hostname())
+}
+
+/// Cloud instance id, from cloud-init data or EC2 instance metadata service
+pub fn instance_id() -> anyhow::Result<String> {
+    if let Some(id) = read_trimmed_opt(&cloud_init_instance_id_path())? {
+        return Ok(id);
+    }
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(2))
+        .build();
+    let token = agent
+        .put(&format!("{EC2_IMDS_URL}/api/token"))
+        .set("X-aws-ec2-metadata-token-ttl-seconds", "60")
+        .call()
+        .context("Failed to get EC2 metadata token")?
+        .into_string()?;
+    Ok(agent
+        .get(&format!("{EC2_IMDS_URL}/meta-data/instance-id"))
+        .set("X-aws-ec2-metadata-token", &token)
+        .call()
+        .context("Failed to get EC2 instance id")?
+        .into_string()?
+        .trim()
+        .to_owned())
+}
+
+/// Instance id, or `None` if not running on a (supported) cloud instance
+fn instance_id_opt() -> Option<String> {
+    instance_id()
+        .map_err(|e| debug!(error = %e, "Instance id not available"))
+        .ok()
+}
+
+/// Value of a host identity `name` (as used in templates and hosts files)
+///
+/// `None` if the value is not available on this host, e.g. the instance id
+/// outside of a cloud.
+fn host_value(name: &str) -> anyhow::Result<Option<String>> {
+    Ok(match name {
+        "hostname" => Some(hostname()?),
+        "machine_id" => Some(machine_id()?),
+        "instance_id" => instance_id_opt(),
+        other => bail!("Unknown host identity `{other}`"),
+    })
+}
+
+/// Render a configuration name template like `web-{hostname}`
+///
+/// Supported placeholders: `{hostname}`, `{machine_id}`, `{instance_id}`.
+/// Returns `None` if a placeholder value is not available on this host.
+pub fn render_template(template: &str) -> anyhow::Result<Option<String>> {
+    render_template_with(template, host_value)
+}
+
+fn render_template_with(
+    template: &str,
+    value: impl Fn(&str) -> anyhow::Result<Option<String>>,
+) -> anyhow::Result<Option<String>> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format_err!("Unterminated placeholder in: {template}"))?;
+        let placeholder = &rest[start + 1..start + end];
+        if !["hostname", "machine_id", "instance_id"].contains(&placeholder) {
+            bail!("Unknown placeholder `{placeholder}` in: {template}");
+        }
+        let Some(value) = value(placeholder)? else {
+            return Ok(None);
+        };
+        out.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(Some(out))
+}
+
+/// Host to configuration mapping file shipped inside the packed flake
+/// (e.g. `hosts.json`)
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct HostsFile {
+    #[serde(default)]
+    hostname: BTreeMap<String, String>,
+    #[serde(default)]
+    machine_id: BTreeMap<String, String>,
+    #[serde(default)]
+    instance_id: BTreeMap<String, String>,
+}
+
+impl HostsFile {
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path.display())),
+        };
+        Ok(Some(serde_json::from_reader(file).with_context(|| {
+            format!("Failed to parse hosts file {}", path.display())
+        })?))
+    }
+
+    /// Find the configuration for the current host, checking hostname, machine
+    /// id and instance id, in that order
+    ///
+    /// Identities not available on this host (e.g. the instance id outside
+    /// of a cloud) just don't match.
+    pub fn lookup(&self) -> anyhow::Result<Option<String>> {
+        self.lookup_with(host_value)
+    }
+
+    fn lookup_with(
+        &self,
+        value: impl Fn(&str) -> anyhow::Result<Option<String>>,
+    ) -> anyhow::Result<Option<String>> {
+        for (name, map) in [
+            ("hostname", &self.hostname),
+            ("machine_id", &self.machine_id),
+            ("instance_id", &self.instance_id),
+        ] {
+            if map.is_empty() {
+                continue;
+            }
+            if let Some(c) = value(name)?.and_then(|value| map.get(&value)) {
+                return Ok(Some(c.clone()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Select the NixOS configuration to activate for this host
+///
+/// In order of precedence:
+///
+/// * entry for this host in the `hosts_file` inside the flake `src`, if
+///   configured (and `src` available),
+/// * rendered `configuration` template,
+/// * `default_configuration`.
+pub fn select_configuration(config: &Config, src: Option<&Path>) -> anyhow::Result<String> {
+    if let (Some(hosts_file), Some(src)) = (config.hosts_file(), src) {
+        let path = src.join(hosts_file);
+        match HostsFile::load(&path)? {
+            Some(hosts) => {
+                if let Some(configuration) = hosts.lookup()? {
+                    debug!(configuration, "Configuration selected by hosts file");
+                    return Ok(configuration);
+                }
+            }
+            None => warn!(path = %path.display(), "Hosts file not found"),
+        }
+    }
+
+    if let Some(template) = config.configuration_template() {
+        match render_template(template) {
+            Ok(Some(configuration)) => return Ok(configuration),
+            Ok(None) => {
+                debug!(
+                    template,
+                    "Configuration template not applicable to this host"
+                );
+            }
+            Err(e) if config.default_configuration().is_some() => {
+                warn!(error = %e, template, "Failed to render configuration template");
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    config
+        .default_configuration()
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| format_err!("configuration not set"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(name: &str) -> anyhow::Result<Option<String>> {
+        Ok(match name {
+            "hostname" => Some("web1".into()),
+            "machine_id" => Some("abcd".into()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn render_template() {
+        assert_eq!(
+            render_template_with("web-{hostname}", values).unwrap(),
+            Some("web-web1".into())
+        );
+        assert_eq!(
+            render_template_with("{hostname}-{machine_id}", values).unwrap(),
+            Some("web1-abcd".into())
+        );
+        assert_eq!(
+            render_template_with("static", values).unwrap(),
+            Some("static".into())
+        );
+        assert_eq!(
+            render_template_with("i-{instance_id}", values).unwrap(),
+            None
+        );
+        assert!(render_template_with("{hostname", values).is_err());
+        assert!(render_template_with("{nope}", values).is_err());
+    }
+
+    #[test]
+    fn lookup() {
+        let hosts: HostsFile = serde_json::from_str(
+            r#"{
+                "hostname": { "other": "a" },
+                "machine_id": { "abcd": "b" },
+                "instance_id": { "i-1": "c" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(hosts.lookup_with(values).unwrap(), Some("b".into()));
+
+        let hosts: HostsFile = serde_json::from_str(
+            r#"{ "hostname": { "web1": "a" }, "machine_id": { "abcd": "b" } }"#,
+        )
+        .unwrap();
+        assert_eq!(hosts.lookup_with(values).unwrap(), Some("a".into()));
+
+        // instance id unavailable is no match, not an error
+        let hosts: HostsFile =
+            serde_json::from_str(r#"{ "instance_id": { "i-1": "c" } }"#).unwrap();
+        assert_eq!(hosts.lookup_with(values).unwrap(), None);
+    }
+}
@@ -0,0 +1,313 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::{host, store};
+
+/// Directory (relative to the remote) holding activation leases
+pub const LEASES_DIR: &str = "leases/";
+
+fn default_lease_secs() -> u64 {
+    60 * 60
+}
+
+fn default_group() -> String {
+    "default".into()
+}
+
+/// Fleet-wide limit of simultaneous activations
+///
+/// Implemented as `limit` lease objects (slots) in the store, per `group`.
+/// A held lease is renewed in the background; one not renewed (or released)
+/// within `lease_secs` (e.g. because the host crashed) can be taken over by
+/// another host. Works with `s3://` and `file://` (e.g. a shared filesystem)
+/// remotes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct ActivationLimit {
+    pub limit: u32,
+    #[serde(default = "default_group")]
+    pub group: String,
+    #[serde(default = "default_lease_secs")]
+    pub lease_secs: u64,
+}
+
+impl ActivationLimit {
+    pub fn new(limit: u32, group: Option<&str>, lease_secs: Option<u64>) -> Self {
+        Self {
+            limit,
+            group: group.map(ToOwned::to_owned).unwrap_or_else(default_group),
+            lease_secs: lease_secs.unwrap_or_else(default_lease_secs),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+struct LeaseRecord {
+    holder: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+    acquired: chrono::DateTime<chrono::Utc>,
+    expires: chrono::DateTime<chrono::Utc>,
+}
+
+impl LeaseRecord {
+    fn with_expires(self, lease_secs: u64) -> Self {
+        let expires = chrono::Utc::now()
+            .checked_add_signed(
+                chrono::Duration::from_std(Duration::from_secs(lease_secs))
+                    .unwrap_or(chrono::Duration::MAX),
+            )
+            .unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC);
+        Self { expires, ..self }
+    }
+}
+
+/// Background renewal of a held lease
+#[derive(Debug)]
+struct Renewer {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+/// An activation slot held by this host; renewed while held, released on
+/// drop
+#[derive(Debug)]
+pub struct Lease {
+    url: Url,
+    region: Option<String>,
+    holder: String,
+    renewer: Option<Renewer>,
+}
+
+impl Lease {
+    fn slot_url(remote: &Url, limit: &ActivationLimit, slot: u32) -> anyhow::Result<Url> {
+        store::dir_url(
+            remote,
+            &format!("{LEASES_DIR}{}/slot-{slot}.json", limit.group),
+        )
+    }
+
+    /// Read a lease along with its etag
+    fn read(url: &Url, region: Option<&str>) -> anyhow::Result<Option<(LeaseRecord, String)>> {
+        let Some((content, etag)) = store::get_object_with_etag(url, region)? else {
+            return Ok(None);
+        };
+        let record = match serde_json::from_slice::<LeaseRecord>(&content) {
+            Ok(record) => record,
+            Err(e) => {
+                // A partially written or otherwise corrupted lease; treat it as expired
+                warn!(%url, error = %e, "Invalid lease");
+                LeaseRecord {
+                    holder: String::new(),
+                    hostname: None,
+                    acquired: chrono::DateTime::<chrono::Utc>::MIN_UTC,
+                    expires: chrono::DateTime::<chrono::Utc>::MIN_UTC,
+                }
+            }
+        };
+        Ok(Some((record, etag)))
+    }
+
+    /// Try to take one of the activation slots; `None` if all are taken
+    ///
+    /// Free slots are created with `If-None-Match`, and expired (or own)
+    /// leases are overwritten with `If-Match` on the etag they were read
+    /// with, so only one host can win any given slot.
+    pub fn try_acquire(
+        remote: &Url,
+        region: Option<&str>,
+        limit: &ActivationLimit,
+    ) -> anyhow::Result<Option<Self>> {
+        Self::try_acquire_as(remote, region, limit, &host::host_id()?)
+    }
+
+    fn try_acquire_as(
+        remote: &Url,
+        region: Option<&str>,
+        limit: &ActivationLimit,
+        holder: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let now = chrono::Utc::now();
+        let record = LeaseRecord {
+            holder: holder.to_owned(),
+            hostname: host::hostname().ok(),
+            acquired: now,
+            expires: now,
+        }
+        .with_expires(limit.lease_secs);
+        let content = serde_json::to_vec_pretty(&record)?;
+
+        let mut candidates = vec![];
+        for slot in 0..limit.limit {
+            let url = Self::slot_url(remote, limit, slot)?;
+            match Self::read(&url, region)? {
+                // We already hold it (e.g. after a restart); just renew it
+                Some((existing, etag)) if existing.holder == holder => {
+                    if store::put_object_if_match(&url, &content, &etag, region)? {
+                        return Ok(Some(Self::new(url, region, record, limit.lease_secs)));
+                    }
+                }
+                Some((existing, etag)) if existing.expires <= now => {
+                    info!(%url, holder = existing.holder, "Taking over expired lease");
+                    candidates.push((url, Some(etag)));
+                }
+                Some(_) => {}
+                None => candidates.push((url, None)),
+            }
+        }
+
+        for (url, etag) in candidates {
+            let acquired = match etag {
+                Some(etag) => store::put_object_if_match(&url, &content, &etag, region)?,
+                None => store::put_object_if_absent(&url, &content, region)?,
+            };
+            if acquired {
+                debug!(%url, "Acquired activation lease");
+                return Ok(Some(Self::new(url, region, record, limit.lease_secs)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn new(url: Url, region: Option<&str>, record: LeaseRecord, lease_secs: u64) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let renewer = {
+            let url = url.clone();
+            let region = region.map(ToOwned::to_owned);
+            let mut record = record.clone();
+            thread::spawn(move || {
+                // Renew well before the lease expires, so a slow or failed
+                // renewal can be retried
+                let interval =
+                    (Duration::from_secs(lease_secs) / 3).max(Duration::from_millis(100));
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    record = record.with_expires(lease_secs);
+                    match Self::renew(&url, region.as_deref(), &record) {
+                        Ok(true) => debug!(%url, "Renewed activation lease"),
+                        Ok(false) => warn!(%url, "Activation lease was taken over"),
+                        Err(e) => warn!(%url, error = %e, "Failed to renew activation lease"),
+                    }
+                }
+            })
+        };
+        Self {
+            url,
+            region: region.map(ToOwned::to_owned),
+            holder: record.holder,
+            renewer: Some(Renewer {
+                stop,
+                thread: renewer,
+            }),
+        }
+    }
+
+    /// Extend the lease, if still held; `false` if it was lost
+    fn renew(url: &Url, region: Option<&str>, record: &LeaseRecord) -> anyhow::Result<bool> {
+        match Self::read(url, region)? {
+            Some((existing, etag)) if existing.holder == record.holder => {
+                store::put_object_if_match(url, &serde_json::to_vec_pretty(record)?, &etag, region)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn release(&self) -> anyhow::Result<()> {
+        let region = self.region.as_deref();
+        if Self::read(&self.url, region)?
+            .is_some_and(|(existing, _)| existing.holder == self.holder)
+        {
+            store::delete_object(&self.url, region)?;
+            debug!(url = %self.url, "Released activation lease");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(renewer) = self.renewer.take() {
+            let _ = renewer.stop.send(());
+            let _ = renewer.thread.join();
+        }
+        if let Err(e) = self.release() {
+            warn!(url = %self.url, error = %e, "Failed to release activation lease");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(dir: &tempfile::TempDir) -> Url {
+        Url::from_file_path(dir.path().join("main")).unwrap()
+    }
+
+    #[test]
+    fn limits_holders() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let remote = remote(&dir);
+        let limit = ActivationLimit::new(1, None, None);
+
+        let lease = Lease::try_acquire_as(&remote, None, &limit, "a")
+            .unwrap()
+            .unwrap();
+        assert!(Lease::try_acquire_as(&remote, None, &limit, "b")
+            .unwrap()
+            .is_none());
+        // Own lease is just renewed, e.g. after a restart
+        let again = Lease::try_acquire_as(&remote, None, &limit, "a")
+            .unwrap()
+            .unwrap();
+        drop(again);
+        drop(lease);
+        assert!(Lease::try_acquire_as(&remote, None, &limit, "b")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn expired_lease_taken_over() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let remote = remote(&dir);
+        let limit = ActivationLimit::new(1, None, Some(0));
+        let url = Lease::slot_url(&remote, &limit, 0).unwrap();
+        let expired = LeaseRecord {
+            holder: "a".into(),
+            hostname: None,
+            acquired: chrono::Utc::now(),
+            expires: chrono::Utc::now(),
+        };
+        store::put_json(&url, &expired, None).unwrap();
+
+        let limit = ActivationLimit::new(1, None, None);
+        let lease = Lease::try_acquire_as(&remote, None, &limit, "b")
+            .unwrap()
+            .unwrap();
+        assert_eq!(lease.holder, "b");
+    }
+
+    #[test]
+    fn renewed_while_held() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let remote = remote(&dir);
+        let limit = ActivationLimit::new(1, None, Some(1));
+
+        let lease = Lease::try_acquire_as(&remote, None, &limit, "a")
+            .unwrap()
+            .unwrap();
+        // Would have expired twice over without the renewal
+        thread::sleep(Duration::from_millis(2500));
+        assert!(Lease::try_acquire_as(&remote, None, &limit, "b")
+            .unwrap()
+            .is_none());
+        drop(lease);
+    }
+}
@@ -1,28 +1,44 @@
 #![doc = include_str!("../README.md")]
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsString;
 use std::fs;
 use std::io::{self, Read, Write};
 use std::ops::ControlFlow;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{self, Stdio};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
 
 use anyhow::{bail, format_err, Context};
 use config::Config;
 use data_dir::DataDir;
-use serde::Deserialize;
-use signal_hook::consts::TERM_SIGNALS;
-use signal_hook::flag;
+use serde::{Deserialize, Serialize};
+use state::State;
 use tracing::{debug, error, info, trace, warn};
 use url::Url;
 
+pub mod cache;
 pub mod config;
+pub mod control;
+pub mod daemon;
 pub mod data_dir;
+pub mod drift;
+pub mod history;
+pub mod host;
+pub mod lease;
+pub mod manifest;
+pub mod metrics;
 pub mod misc;
+pub mod observe;
 pub mod opts;
+pub mod plan;
+pub mod reboot;
+pub mod report;
+pub mod rollout;
+pub mod socket;
+pub mod state;
+pub mod status;
+pub mod store;
+pub mod systemd;
 
 pub trait CommandExt {
     fn log_debug(&mut self) -> &mut Self;
@@ -51,6 +67,58 @@ pub fn nixos_rebuild_path() -> OsString {
     std::env::var_os("NPCNIX_NIXOS_REBUILD").unwrap_or_else(|| OsString::from("nixos-rebuild"))
 }
 
+pub fn nix_env_path() -> OsString {
+    std::env::var_os("NPCNIX_NIX_ENV").unwrap_or_else(|| OsString::from("nix-env"))
+}
+
+pub fn nix_path() -> OsString {
+    std::env::var_os("NPCNIX_NIX").unwrap_or_else(|| OsString::from("nix"))
+}
+
+pub fn nix_store_dir() -> PathBuf {
+    std::env::var_os("NIX_STORE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/nix/store"))
+}
+
+pub fn shutdown_path() -> OsString {
+    std::env::var_os("NPCNIX_SHUTDOWN").unwrap_or_else(|| OsString::from("shutdown"))
+}
+
+pub fn booted_system_path() -> PathBuf {
+    std::env::var_os("NPCNIX_BOOTED_SYSTEM")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/run/booted-system"))
+}
+
+pub fn current_system_path() -> PathBuf {
+    std::env::var_os("NPCNIX_CURRENT_SYSTEM")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/run/current-system"))
+}
+
+pub fn system_profile_path() -> PathBuf {
+    std::env::var_os("NPCNIX_SYSTEM_PROFILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/nix/var/nix/profiles/system"))
+}
+
+/// Generation number of the NixOS system profile, as encoded in its
+/// `system-<N>-link` target
+pub fn system_profile_generation() -> anyhow::Result<Option<u64>> {
+    let target = match fs::read_link(system_profile_path()) {
+        Ok(target) => target,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("Failed to read system profile link"),
+    };
+    Ok(target
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("system-"))
+        .and_then(|name| name.strip_suffix("-link"))
+        .and_then(|generation| generation.parse().ok()))
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Once {
     Any,
@@ -64,21 +132,28 @@ pub fn pull(remote: &Url, dst: &Path) -> anyhow::Result<()> {
         _ => anyhow::bail!("Protocol not supported: {scheme}"),
     };
 
-    unpack_archive_to(reader, dst)?;
+    unpack_archive_to(metrics::DownloadCounter::new(reader), dst)?;
     child.wait()?;
 
     Ok(())
 }
 
-pub fn push(src: &Path, include: &HashSet<OsString>, remote: &url::Url) -> anyhow::Result<()> {
+pub fn push(
+    src: &Path,
+    include: &HashSet<OsString>,
+    revision: Option<&str>,
+    remote: &url::Url,
+) -> anyhow::Result<()> {
     verify_flake_src(src)?;
+    let manifest = manifest::Manifest::new(src, revision);
     let scheme = remote.scheme();
     let (mut writer, mut child) = match scheme {
         "s3" => push_s3(remote)?,
         _ => anyhow::bail!("Protocol not supported: {scheme}"),
     };
 
-    pack_archive_from(src, include, &mut writer).context("Failed to pack the src archive")?;
+    pack_archive_from(src, include, &manifest, &mut writer)
+        .context("Failed to pack the src archive")?;
     writer.flush()?;
     drop(writer);
 
@@ -95,10 +170,83 @@ pub fn get_etag(remote: &Url, config: &Config) -> anyhow::Result<String> {
     })
 }
 
-#[derive(Debug, Clone)]
+/// Extra settings of activations, mostly passed to `nixos-rebuild`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub struct ActivateOpts {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub extra_substituters: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub extra_trusted_public_keys: Vec<String>,
+    /// Arbitrary `--option <key> <value>` pairs
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_jobs: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub builders: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cores: Option<u32>,
+}
+
+impl ActivateOpts {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Arguments to pass to `nix` / `nixos-rebuild`
+    pub fn nix_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for subscriber in &self.extra_substituters {
+            args.extend([
+                "--option".into(),
+                "extra-substituters".into(),
+                subscriber.clone(),
+            ]);
+        }
+        for key in &self.extra_trusted_public_keys {
+            args.extend([
+                "--option".into(),
+                "extra-trusted-public-keys".into(),
+                key.clone(),
+            ]);
+        }
+        for (key, value) in &self.options {
+            args.extend(["--option".into(), key.clone(), value.clone()]);
+        }
+        if let Some(ref max_jobs) = self.max_jobs {
+            args.extend(["--max-jobs".into(), max_jobs.clone()]);
+        }
+        if let Some(ref builders) = self.builders {
+            args.extend(["--builders".into(), builders.clone()]);
+        }
+        if let Some(cores) = self.cores {
+            args.extend(["--cores".into(), cores.to_string()]);
+        }
+        args
+    }
+
+    /// Combine with `other`, which takes precedence on conflicting settings
+    pub fn merge(self, other: &Self) -> Self {
+        let mut options = self.options;
+        options.extend(other.options.clone());
+        Self {
+            extra_substituters: self
+                .extra_substituters
+                .into_iter()
+                .chain(other.extra_substituters.iter().cloned())
+                .collect(),
+            extra_trusted_public_keys: self
+                .extra_trusted_public_keys
+                .into_iter()
+                .chain(other.extra_trusted_public_keys.iter().cloned())
+                .collect(),
+            options,
+            max_jobs: other.max_jobs.clone().or(self.max_jobs),
+            builders: other.builders.clone().or(self.builders),
+            cores: other.cores.or(self.cores),
+        }
+    }
 }
 
 pub fn with_activate_lock<T>(
@@ -124,7 +272,7 @@ pub fn with_activate_lock<T>(
         }
         Err(e) => {
             if e.kind() != io::ErrorKind::WouldBlock {
-                return Err(e)?;
+                return Err(e.into());
             }
 
             warn!("Waiting for another instance to finish");
@@ -143,18 +291,109 @@ pub fn activate(
 ) -> Result<(), anyhow::Error> {
     with_activate_lock(data_dir, || {
         // Note: we load every time, in case settings changed
-        activate_inner(src, configuration, activate_opts)?;
-        data_dir
-            .map(|data_dir| data_dir.update_last_reconfiguration(configuration, ""))
-            .transpose()
+        let config = data_dir.map(DataDir::load_config).transpose()?;
+        let activate_opts = config
+            .as_ref()
+            .map(|config| config.activate_opts().clone().merge(activate_opts))
+            .unwrap_or_else(|| activate_opts.clone());
+        let plan = config.as_ref().is_some_and(Config::plan);
+        let Some(data_dir) = data_dir else {
+            return activate_inner(src, configuration, &activate_opts, plan, None);
+        };
+        let mut entry = history::Entry::start(
+            data_dir,
+            history::ActivationMode::Manual,
+            configuration,
+            None,
+        );
+        let res = activate_inner(src, configuration, &activate_opts, plan, entry.as_mut());
+        if let Some(entry) = entry {
+            entry.finish(&res);
+        }
+        res?;
+        data_dir.update_last_reconfiguration(configuration, "")
     })?;
     Ok(())
 }
 
+/// Re-activate a previously activated remote version from the local cache,
+/// and pin it so `follow` doesn't move away from it
+///
+/// Without `to`, the last successfully activated version before the current
+/// one is used.
+pub fn rollback(
+    data_dir: &DataDir,
+    to: Option<&str>,
+    activate_opts: &ActivateOpts,
+) -> anyhow::Result<()> {
+    with_activate_lock(Some(data_dir), || {
+        let config = data_dir.load_config()?;
+        let state = data_dir.load_state()?;
+        let cache = cache::SourceCache::new(data_dir);
+        let records = history::list(data_dir)?;
+        let successful = || {
+            records
+                .iter()
+                .rev()
+                .filter(|record| record.outcome == history::Outcome::Success)
+        };
+
+        let etag = match to {
+            Some(etag) => etag.to_owned(),
+            None => successful()
+                .filter_map(|record| record.etag.as_deref())
+                .find(|etag| *etag != state.last_etag())
+                .ok_or_else(|| format_err!("No previous version to roll back to"))?
+                .to_owned(),
+        };
+        let src = cache
+            .get(&etag)?
+            .ok_or_else(|| format_err!("Version {etag} is not cached"))?;
+        let configuration =
+            match successful().find(|record| record.etag.as_deref() == Some(etag.as_str())) {
+                Some(record) => record.configuration.clone(),
+                None => host::select_configuration(&config, Some(&src))?,
+            };
+        let src = import_source(&src);
+
+        info!(etag, configuration, "Rolling back");
+        let activate_opts = config.activate_opts().clone().merge(activate_opts);
+        let mut entry = history::Entry::start(
+            data_dir,
+            history::ActivationMode::Rollback,
+            &configuration,
+            Some(&etag),
+        );
+        let res = activate_inner(
+            &src,
+            &configuration,
+            &activate_opts,
+            config.plan(),
+            entry.as_mut(),
+        );
+        if let Some(entry) = entry {
+            entry.finish(&res);
+        }
+        res?;
+        keep_active_source(data_dir, &src);
+        data_dir.update_last_reconfiguration(&configuration, &etag)?;
+        data_dir.update_config(|config| Ok(config.with_pinned(&etag)))?;
+        info!(
+            etag,
+            "Pinned the rolled back version; use `npcnix unpin` to follow the remote again"
+        );
+        Ok(())
+    })
+}
+
+/// Activate `configuration` from `src`; with `plan`, log (and record in the
+/// history) a [`plan::Plan`] before switching
 fn activate_inner(
     src: &Path,
     configuration: &str,
     activate_opts: &ActivateOpts,
+    plan: bool,
+    history_entry: Option<&mut history::Entry>,
 ) -> Result<(), anyhow::Error> {
     verify_flake_src(src)?;
     info!(
@@ -163,30 +402,306 @@ fn activate_inner(
         "Activating configuration"
     );
     let mut cmd = process::Command::new(nixos_rebuild_path());
-    cmd.args(["switch", "-L"]);
+    cmd.args(["switch", "-L"]).args(activate_opts.nix_args());
 
-    for subscriber in &activate_opts.extra_substituters {
-        cmd.args(["--option", "extra-substituters", subscriber]);
+    cmd.args(["--flake", &format!(".#{configuration}")])
+        .current_dir(src);
+    if src.starts_with(nix_store_dir()) {
+        // Read-only
+        cmd.arg("--no-write-lock-file");
     }
-    for key in &activate_opts.extra_trusted_public_keys {
-        cmd.args(["--option", "extra-trusted-public-keys", key]);
+
+    let start = std::time::Instant::now();
+    let res = switch_unless_current(
+        src,
+        configuration,
+        activate_opts,
+        plan,
+        &mut cmd,
+        history_entry,
+    );
+    metrics::record_activation(start.elapsed(), res.is_ok());
+    // Whatever follows (e.g. talking to the remote) is subject to the watchdog
+    // again
+    daemon::set_phase(daemon::Phase::Checking);
+    res
+}
+
+/// Build the toplevel first and only run `cmd` (`nixos-rebuild switch`) if
+/// it differs from the running system, to avoid needlessly re-running the
+/// activation script (e.g. after a configuration rename or `--ignore-etag`)
+fn switch_unless_current(
+    src: &Path,
+    configuration: &str,
+    activate_opts: &ActivateOpts,
+    plan: bool,
+    cmd: &mut process::Command,
+    mut history_entry: Option<&mut history::Entry>,
+) -> anyhow::Result<()> {
+    let system = build_toplevel_inner(
+        src,
+        configuration,
+        activate_opts,
+        history_entry.as_deref().map(history::Entry::log),
+    )?;
+    if drift::current_system()?.as_ref() == Some(&system) {
+        // E.g. after a manual `nixos-rebuild test`, the system is running,
+        // but a reboot would revert it
+        if drift::profile_system()?.as_ref() == Some(&system) {
+            info!(system = %system.display(), "System already running; skipping switch");
+            if let Some(mut log) = history_entry.as_deref().map(history::Entry::log) {
+                let _ = writeln!(log, "{} already running; skipping switch", system.display());
+            }
+            return Ok(());
+        }
+        info!(system = %system.display(), "System already running, but not the boot default; updating the system profile");
+        if let Some(mut log) = history_entry.as_deref().map(history::Entry::log) {
+            let _ = writeln!(
+                log,
+                "{} already running; updating the system profile",
+                system.display()
+            );
+        }
+        daemon::set_phase(daemon::Phase::Switching);
+        return set_boot_default(&system, history_entry);
     }
 
-    cmd.args(["--flake", &format!(".#{configuration}")])
-        .current_dir(src);
+    if plan {
+        match plan::Plan::compute(configuration, &system) {
+            Ok(plan) => {
+                info!(configuration, summary = plan.summary(), "Switching");
+                if let Some(entry) = history_entry.as_deref_mut() {
+                    let _ = write!(entry.log(), "{plan}");
+                    entry.set_plan(plan);
+                }
+            }
+            Err(e) => warn!(error = %e, "Failed to compute the plan"),
+        }
+    }
 
-    let status = cmd
-        .log_debug()
-        .status()
-        .context("Calling `nixos-rebuild` failed")?;
+    // The system is already built, so `nixos-rebuild` mostly just switches;
+    // set the phase before spawning it, so it can't be aborted half-way
+    daemon::set_phase(daemon::Phase::Switching);
+    let (status, _) = daemon::run_tracked(
+        cmd,
+        false,
+        history_entry.as_deref().map(history::Entry::log),
+    )
+    .context("Calling `nixos-rebuild` failed")?;
+    if let Some(entry) = history_entry {
+        entry.set_exit_status(status);
+    }
     if !status.success() {
         bail!("nixos-rebuild returned exit code={:?}", status.code());
     }
     Ok(())
 }
 
-pub fn pack(src: &Path, include: &HashSet<OsString>, dst: &Path) -> anyhow::Result<()> {
+/// Make the already running `system` the default boot entry, like the
+/// profile and bootloader part of `nixos-rebuild switch`
+fn set_boot_default(
+    system: &Path,
+    history_entry: Option<&mut history::Entry>,
+) -> anyhow::Result<()> {
+    let log = history_entry.as_deref().map(history::Entry::log);
+    let (status, _) = daemon::run_tracked(
+        process::Command::new(nix_env_path())
+            .arg("--profile")
+            .arg(system_profile_path())
+            .arg("--set")
+            .arg(system),
+        false,
+        log,
+    )
+    .context("Calling `nix-env` failed")?;
+    if !status.success() {
+        bail!("nix-env returned exit code={:?}", status.code());
+    }
+    let (status, _) = daemon::run_tracked(
+        process::Command::new(system.join("bin/switch-to-configuration")).arg("boot"),
+        false,
+        log,
+    )
+    .context("Calling `switch-to-configuration` failed")?;
+    if let Some(entry) = history_entry {
+        entry.set_exit_status(status);
+    }
+    if !status.success() {
+        bail!(
+            "switch-to-configuration boot returned exit code={:?}",
+            status.code()
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn nix_cmd() -> process::Command {
+    let mut cmd = process::Command::new(nix_path());
+    cmd.args(["--extra-experimental-features", "nix-command flakes"]);
+    cmd
+}
+
+fn nix_toplevel_cmd(
+    src: &Path,
+    configuration: &str,
+    activate_opts: &ActivateOpts,
+    args: &[&str],
+) -> process::Command {
+    let mut cmd = nix_cmd();
+    cmd.args(args)
+        .args(activate_opts.nix_args())
+        .arg(format!(
+            ".#nixosConfigurations.\"{configuration}\".config.system.build.toplevel"
+        ))
+        .current_dir(src);
+    if src.starts_with(nix_store_dir()) {
+        cmd.arg("--no-write-lock-file");
+    }
+    cmd
+}
+
+pub(crate) fn run_nix_for_stdout(
+    mut cmd: process::Command,
+    log: Option<&fs::File>,
+) -> anyhow::Result<String> {
+    let (status, stdout) =
+        daemon::run_tracked(&mut cmd, true, log).context("Calling `nix` failed")?;
+    if !status.success() {
+        bail!("nix returned exit code={:?}", status.code());
+    }
+    String::from_utf8(stdout).context("Invalid `nix` output")
+}
+
+fn run_nix_for_path(cmd: process::Command, log: Option<&fs::File>) -> anyhow::Result<PathBuf> {
+    let path = run_nix_for_stdout(cmd, log)?;
+    let path = path.trim();
+    if path.is_empty() {
+        bail!("nix returned no store path");
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// Import an unpacked flake source into the Nix store
+///
+/// Identical sources get the same (content-addressed) path, so Nix can reuse
+/// its evaluation cache instead of evaluating e.g. nixpkgs from scratch.
+/// Falls back to using `src` directly if the import fails.
+pub fn import_source(src: &Path) -> PathBuf {
+    let mut cmd = nix_cmd();
+    cmd.args(["store", "add-path", "--name", "npcnix-source"])
+        .arg(src);
+    match run_nix_for_path(cmd, None) {
+        Ok(path) => {
+            debug!(src = %src.display(), path = %path.display(), "Imported source into the Nix store");
+            path
+        }
+        Err(e) => {
+            warn!(src = %src.display(), error = %e, "Failed to import source into the Nix store");
+            src.to_owned()
+        }
+    }
+}
+
+/// Make `root` a Nix GC root (symlink) keeping the store `path` alive
+pub fn add_gc_root(path: &Path, root: &Path) -> anyhow::Result<()> {
+    if !path.starts_with(nix_store_dir()) {
+        return Ok(());
+    }
+    let mut cmd = nix_cmd();
+    cmd.args(["build", "--out-link"]).arg(root).arg(path);
+    let status = cmd.log_debug().status().context("Calling `nix` failed")?;
+    if !status.success() {
+        bail!("nix returned exit code={:?}", status.code());
+    }
+    Ok(())
+}
+
+/// Build `configuration` from `src` (default: the remote's current version)
+/// and compare it with the running system
+pub fn plan(
+    data_dir: &DataDir,
+    src: Option<&Path>,
+    configuration: Option<&str>,
+    activate_opts: &ActivateOpts,
+) -> anyhow::Result<plan::Plan> {
+    let config = data_dir.load_config()?;
+    let src = match src {
+        Some(src) => src.to_owned(),
+        None => {
+            let remote = config.remote()?;
+            let etag = get_etag(remote, &config)?;
+            info!(etag, "Planning remote version");
+            import_source(&cache::SourceCache::new(data_dir).fetch(&config, &etag)?)
+        }
+    };
+    let configuration = match configuration {
+        Some(configuration) => configuration.to_owned(),
+        None => host::select_configuration(&config, Some(&src))?,
+    };
+    let activate_opts = config.activate_opts().clone().merge(activate_opts);
+    let system = build_toplevel(&src, &configuration, &activate_opts)?;
+    plan::Plan::compute(&configuration, &system)
+}
+
+/// Point the data dir GC root at the (imported) source of the active
+/// generation, so it survives `nix-collect-garbage` until replaced
+fn keep_active_source(data_dir: &DataDir, src: &Path) {
+    if let Err(e) = add_gc_root(src, &data_dir.source_gc_root_path()) {
+        warn!(error = %e, "Failed to add GC root for the active source");
+    }
+}
+
+/// Evaluate (without building) the store path of the system `configuration`
+pub fn eval_toplevel(
+    src: &Path,
+    configuration: &str,
+    activate_opts: &ActivateOpts,
+) -> anyhow::Result<PathBuf> {
+    verify_flake_src(src)?;
+    daemon::set_phase(daemon::Phase::Evaluating);
+    run_nix_for_path(
+        nix_toplevel_cmd(src, configuration, activate_opts, &["eval", "--raw"]),
+        None,
+    )
+}
+
+/// Build the system `configuration`, returning its store path
+pub fn build_toplevel(
+    src: &Path,
+    configuration: &str,
+    activate_opts: &ActivateOpts,
+) -> anyhow::Result<PathBuf> {
+    build_toplevel_inner(src, configuration, activate_opts, None)
+}
+
+fn build_toplevel_inner(
+    src: &Path,
+    configuration: &str,
+    activate_opts: &ActivateOpts,
+    log: Option<&fs::File>,
+) -> anyhow::Result<PathBuf> {
+    verify_flake_src(src)?;
+    info!(configuration, src = %src.display(), "Building configuration");
+    daemon::set_phase(daemon::Phase::Building);
+    run_nix_for_path(
+        nix_toplevel_cmd(
+            src,
+            configuration,
+            activate_opts,
+            &["build", "-L", "--no-link", "--print-out-paths"],
+        ),
+        log,
+    )
+}
+
+pub fn pack(
+    src: &Path,
+    include: &HashSet<OsString>,
+    revision: Option<&str>,
+    dst: &Path,
+) -> anyhow::Result<()> {
     verify_flake_src(src)?;
+    let manifest = manifest::Manifest::new(src, revision);
 
     let tmp_dst = dst.with_extension("tmp");
     let file = fs::OpenOptions::new()
@@ -197,7 +712,7 @@ pub fn pack(src: &Path, include: &HashSet<OsString>, dst: &Path) -> anyhow::Resu
         .with_context(|| format!("Could not create temporary file: {}", tmp_dst.display()))?;
     let mut writer = io::BufWriter::new(&file);
 
-    pack_archive_from(src, include, &mut writer)
+    pack_archive_from(src, include, &manifest, &mut writer)
         .with_context(|| format!("Failed to pack the src archive: {}", src.display()))?;
     writer.flush()?;
     drop(writer);
@@ -313,6 +828,7 @@ fn unpack_archive_to(reader: impl Read, dst: &Path) -> io::Result<()> {
 fn pack_archive_from(
     src: &Path,
     include: &HashSet<OsString>,
+    manifest: &manifest::Manifest,
     writer: impl Write,
 ) -> io::Result<()> {
     let encoder = zstd::stream::Encoder::new(writer, 0)?;
@@ -324,6 +840,10 @@ fn pack_archive_from(
         let file_name = path
             .file_name()
             .expect("read_dir must return only items with valid file_name");
+        if file_name == manifest::MANIFEST_FILE {
+            warn!(src = %path.display(), "Ignoring existing manifest");
+            continue;
+        }
         let metadata = path.symlink_metadata()?;
         trace!(
             src = %path.display(),
@@ -359,6 +879,11 @@ fn pack_archive_from(
             warn!(src = %path.display(), "Ignoring unknown file type");
         }
     }
+    let manifest = serde_json::to_vec_pretty(manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, manifest::MANIFEST_FILE, manifest.as_slice())?;
     builder.into_inner()?.finish()?;
 
     Ok(())
@@ -371,20 +896,23 @@ pub fn follow(
     once: Option<Once>,
     ignore_etag: bool,
 ) -> anyhow::Result<()> {
-    let shutdown_requested = Arc::new(AtomicBool::new(false));
-    let shutdown_on_signal = Arc::new(AtomicBool::new(false));
-
-    for sig in TERM_SIGNALS {
-        // On first signal, mark shutdown as requested
-        flag::register(*sig, Arc::clone(&shutdown_requested))?;
-        // Also make the second signal shutdown immediately
-        flag::register(*sig, Arc::clone(&shutdown_on_signal))?;
+    let wake = daemon::Wake::install()?;
+    let _pid_file = daemon::PidFile::create(&data_dir.pid_file_path())?;
+    let _socket = socket::Server::start(data_dir, &wake)?;
+    if let Err(e) = metrics::load_counters(data_dir) {
+        warn!(error = %e, "Failed to load metrics counters");
+    }
+    metrics::serve(data_dir)?;
 
-        // If shutdown_on_signal was already set, shutdown immediately on signal
-        flag::register_conditional_shutdown(*sig, 1, Arc::clone(&shutdown_on_signal))?;
+    data_dir.load_config()?;
+    let state = data_dir.load_state()?;
+    if let Some(etag) = state.remote_etag() {
+        daemon::set_etag(etag);
     }
+    systemd::notify("READY=1");
+    systemd::start_watchdog();
 
-    while !shutdown_requested.load(Ordering::SeqCst) {
+    while !wake.shutdown_requested() {
         if let ControlFlow::Break(()) = follow_inner(
             data_dir,
             activate_opts,
@@ -397,11 +925,10 @@ pub fn follow(
 
         // reload the config, just in case it changed in the meantime
         let config = data_dir.load_config()?;
-        // During sleep, shutdown immediately on any signal
-        shutdown_on_signal.store(true, Ordering::SeqCst);
-        config.rng_sleep();
-        shutdown_on_signal.store(false, Ordering::SeqCst);
+        let state = data_dir.load_state()?;
+        config.rng_sleep(&state, &wake);
     }
+    systemd::notify("STOPPING=1");
     Ok(())
 }
 
@@ -412,25 +939,47 @@ fn follow_inner(
     once: Option<Once>,
     ignore_etag: bool,
 ) -> Result<ControlFlow<(), ()>, anyhow::Error> {
-    with_activate_lock(Some(data_dir), || {
+    let res = with_activate_lock(Some(data_dir), || {
         // Note: we load every time, in case settings changed
         let config = data_dir.load_config()?;
+        let state = data_dir.load_state()?;
 
         if config.is_paused() {
-            info!("Paused");
+            info!(status = config.status_string(), "Paused");
         } else {
-            match follow_inner_try(&config, activate_opts, override_configuration, ignore_etag) {
-                Ok(res) => {
-                    match res {
-                        Some((ref configuration, ref etag)) => {
-                            data_dir.update_last_reconfiguration(configuration, etag)?;
-                            info!(etag, "Successfully activated new configuration");
+            match follow_inner_try(
+                data_dir,
+                &config,
+                &state,
+                activate_opts,
+                override_configuration,
+                ignore_etag,
+            ) {
+                Ok(check) => {
+                    data_dir.record_check_succeeded(&check.etag, !check.deferred)?;
+                    match check.activated {
+                        Some(ref activated) => {
+                            data_dir.update_last_reconfiguration(
+                                &activated.configuration,
+                                &activated.etag,
+                            )?;
+                            info!(
+                                etag = activated.etag,
+                                "Successfully activated new configuration"
+                            );
+                            if let Err(e) = reboot::handle_after_activation(config.reboot_policy())
+                            {
+                                error!(error = %e, "Failed to handle reboot policy");
+                            }
                         }
                         None => {
                             debug!("Remote not changed");
+                            if let Err(e) = reboot::handle_periodic(config.reboot_policy()) {
+                                error!(error = %e, "Failed to handle reboot policy");
+                            }
                         }
                     }
-                    match (once, res.is_some()) {
+                    match (once, check.activated.is_some()) {
                         (None, _) => {}
                         (Some(Once::Activate), false) => {}
                         (Some(Once::Any), _) | (Some(Once::Activate), true) => {
@@ -439,32 +988,397 @@ fn follow_inner(
                         }
                     }
                 }
-                Err(e) => error!(error = %e, "Failed to activate new configuration"),
+                Err(e) => {
+                    error!(error = %e, "Failed to activate new configuration");
+                    data_dir.record_error(&format!("{e:#}"))?;
+                }
             }
         }
         Ok(ControlFlow::Continue(()))
-    })
+    });
+
+    daemon::set_phase(daemon::Phase::Reporting);
+    if let Err(e) = report::publish(data_dir) {
+        warn!(error = %e, "Failed to publish report");
+    }
+    if let Err(e) = metrics::store_counters(data_dir) {
+        warn!(error = %e, "Failed to store metrics counters");
+    }
+    if let Err(e) = metrics::publish(data_dir) {
+        warn!(error = %e, "Failed to write metrics");
+    }
+
+    res
+}
+
+/// Result of a successful [`follow_inner_try`]
+#[derive(Debug, Clone)]
+pub struct FollowCheck {
+    /// Current etag of the remote
+    pub etag: String,
+    /// Version activated during the check, if any
+    pub activated: Option<Activated>,
+    /// The check stopped before establishing the host is up to date (paused,
+    /// pinned, backing off, waiting for the rollout or an activation slot)
+    pub deferred: bool,
+}
+
+/// Remote version activated by [`follow_inner_try`]
+#[derive(Debug, Clone)]
+pub struct Activated {
+    pub configuration: String,
+    /// Etag of the activated version; differs from the remote etag when
+    /// reconverging a pinned version
+    pub etag: String,
+}
+
+/// Evaluate (or build) the remote version without activating it, and
+/// record whether the host is running it
+///
+/// Failed evaluations (or builds) back off like failed activations; returns
+/// `true` if the check was deferred because of that.
+#[allow(clippy::too_many_arguments)]
+fn observe(
+    data_dir: &DataDir,
+    config: &Config,
+    state: &State,
+    activate_opts: &ActivateOpts,
+    configuration: Option<String>,
+    etag: &str,
+    build: bool,
+    ignore_etag: bool,
+) -> anyhow::Result<bool> {
+    let already_observed = state.observation().filter(|observation| {
+        observation.etag == etag
+            && observation.built == build
+            && configuration.as_deref().map_or(true, |configuration| {
+                observation.configuration == configuration
+            })
+    });
+    let observation = match already_observed {
+        Some(observation) if !ignore_etag => observation.clone(),
+        _ => {
+            if !ignore_etag {
+                if let Some(retry_at) =
+                    state.activation_retry_at(config, configuration.as_deref(), etag)
+                {
+                    info!(
+                        etag,
+                        %retry_at,
+                        "Previous evaluation of this remote version failed; backing off"
+                    );
+                    return Ok(true);
+                }
+            }
+            let activate_opts = config.activate_opts().clone().merge(activate_opts);
+            let cache = cache::SourceCache::new(data_dir);
+            let src = cache.fetch(config, etag)?;
+            if let Err(e) = cache.gc(config.max_cached_sources(), &[state.last_etag(), etag]) {
+                warn!(error = %e, "Failed to clean up cached sources");
+            }
+            let configuration = match configuration {
+                Some(configuration) => configuration,
+                None => host::select_configuration(config, Some(&src))?,
+            };
+            let src = self::import_source(&src);
+            // Only failures of the evaluation itself count towards backing off
+            let system = match if build {
+                self::build_toplevel(&src, &configuration, &activate_opts)
+            } else {
+                self::eval_toplevel(&src, &configuration, &activate_opts)
+            } {
+                Ok(system) => system,
+                Err(e) => {
+                    data_dir.record_activation_failure(&configuration, etag, &format!("{e:#}"))?;
+                    return Err(e);
+                }
+            };
+            let observation = observe::Observation {
+                configuration,
+                etag: etag.to_owned(),
+                system,
+                built: build,
+                time: chrono::Utc::now(),
+            };
+            data_dir.record_observation(observation.clone())?;
+            observation
+        }
+    };
+
+    if observation.is_up_to_date()? {
+        debug!(etag, system = %observation.system.display(), "Host is up to date");
+    } else {
+        warn!(
+            etag,
+            configuration = observation.configuration,
+            system = %observation.system.display(),
+            "Host is not running the remote version (observe-only mode)"
+        );
+    }
+    Ok(false)
 }
 
 pub fn follow_inner_try(
+    data_dir: &DataDir,
     config: &Config,
+    state: &State,
     activate_opts: &ActivateOpts,
     override_configuration: Option<&str>,
     ignore_etag: bool,
-) -> anyhow::Result<Option<(String, String)>> {
-    let configuration = override_configuration
-        .map(Ok)
-        .unwrap_or_else(|| config.configuration())?;
+) -> anyhow::Result<FollowCheck> {
+    daemon::set_phase(daemon::Phase::Checking);
+    // With a hosts file, configuration is known only after the flake was pulled
+    let configuration = match override_configuration {
+        Some(configuration) => Some(configuration.to_owned()),
+        None if config.hosts_file().is_some() => None,
+        None => Some(host::select_configuration(config, None)?),
+    };
+
+    let etag = metrics::track_remote("etag", self::get_etag(config.remote()?, config))?;
+    daemon::set_etag(&etag);
+
+    let control = metrics::track_remote(
+        "control",
+        control::Control::fetch(config.remote()?, config.region_opt()),
+    )?;
+    let control_configuration = configuration
+        .as_deref()
+        .unwrap_or_else(|| state.last_configuration());
+    if let Some(pause) = control.pause_for(control_configuration) {
+        info!(
+            until = ?pause.until,
+            info = %pause.info,
+            "Paused by the remote control document"
+        );
+        return Ok(FollowCheck {
+            etag,
+            activated: None,
+            deferred: true,
+        });
+    }
+    let is_observe = matches!(config.mode(), observe::FollowMode::Observe { .. });
+    // Drift is checked before the pins, so a pinned host is reconverged too
+    let reconverge = match (is_observe, drift::detect(state)?) {
+        (false, Some(drift)) => {
+            warn!(
+                expected = %drift.expected.display(),
+                actual = %drift.actual.display(),
+                policy = ?config.drift_policy(),
+                "System drifted from the last activated configuration"
+            );
+            config.drift_policy() == drift::DriftPolicy::Reconverge
+        }
+        _ => false,
+    };
+
+    let pinned = match control.pinned_etag(control_configuration) {
+        Some(pinned) if pinned != etag => {
+            info!(
+                pinned,
+                etag, "Pinned to a different version by the remote control document"
+            );
+            Some(pinned)
+        }
+        _ => match config.pinned() {
+            Some(pinned) if pinned.etag != etag => {
+                info!(pinned = pinned.etag, etag, "Holding locally pinned version");
+                Some(pinned.etag.as_str())
+            }
+            _ => None,
+        },
+    };
+    // Version to activate: the remote one, or the pinned one when reconverging
+    let target = match pinned {
+        Some(pinned) if reconverge => pinned.to_owned(),
+        Some(_) => {
+            return Ok(FollowCheck {
+                etag,
+                activated: None,
+                deferred: true,
+            });
+        }
+        None => etag.clone(),
+    };
+
+    if let observe::FollowMode::Observe { build } = config.mode() {
+        let deferred = self::observe(
+            data_dir,
+            config,
+            state,
+            activate_opts,
+            configuration,
+            &etag,
+            build,
+            ignore_etag,
+        )?;
+        return Ok(FollowCheck {
+            etag,
+            activated: None,
+            deferred,
+        });
+    }
+
+    if !ignore_etag
+        && !reconverge
+        && state.last_etag() == target
+        && configuration.as_deref().map_or(true, |configuration| {
+            state.last_configuration() == configuration
+        })
+    {
+        return Ok(FollowCheck {
+            etag,
+            activated: None,
+            deferred: false,
+        });
+    }
+
+    if !ignore_etag {
+        if let Some(retry_at) = state.activation_retry_at(config, configuration.as_deref(), &target)
+        {
+            info!(
+                etag = target,
+                %retry_at,
+                "Previous activation of this remote version failed; backing off"
+            );
+            return Ok(FollowCheck {
+                etag,
+                activated: None,
+                deferred: true,
+            });
+        }
+    }
 
-    let etag = self::get_etag(config.remote()?, config)?;
+    if !ignore_etag && target == etag && !state.last_etag().is_empty() && state.last_etag() != etag
+    {
+        if let Some(rollout) = metrics::track_remote(
+            "rollout",
+            rollout::Rollout::fetch(config.remote()?, config.region_opt()),
+        )? {
+            if rollout.applies_to(&etag)
+                && !rollout.includes_host(&host::host_id()?, host::hostname().ok().as_deref())
+            {
+                info!(
+                    etag,
+                    percentage = rollout.cur_percentage(chrono::Utc::now()),
+                    "Host not selected for the rollout yet; staying on the current version"
+                );
+                return Ok(FollowCheck {
+                    etag,
+                    activated: None,
+                    deferred: true,
+                });
+            }
+        }
+    }
 
-    if !ignore_etag && config.last_configuration() == configuration && config.last_etag() == etag {
-        return Ok(None);
+    let _lease = match config.activation_limit() {
+        Some(limit) => {
+            match metrics::track_remote(
+                "lease",
+                lease::Lease::try_acquire(config.remote()?, config.region_opt(), limit),
+            )? {
+                Some(lease) => Some(lease),
+                None => {
+                    info!(
+                        limit = limit.limit,
+                        group = limit.group,
+                        "All fleet activation slots are taken; will retry"
+                    );
+                    return Ok(FollowCheck {
+                        etag,
+                        activated: None,
+                        deferred: true,
+                    });
+                }
+            }
+        }
+        None => None,
+    };
+
+    let activate_opts = config.activate_opts().clone().merge(activate_opts);
+    let cache = cache::SourceCache::new(data_dir);
+    // Only failures of the activation itself count towards backing off this
+    // version; fetch errors are retried on the next iteration
+    let src = if target == etag {
+        cache.fetch(config, &etag)
+    } else {
+        info!(etag = target, "Reconverging to the pinned version");
+        cache
+            .get(&target)?
+            .ok_or_else(|| format_err!("Pinned version {target} is not cached; can't reconverge"))
+    };
+    let res = src.and_then(|src| {
+        let configuration = match configuration {
+            Some(configuration) => configuration,
+            None => host::select_configuration(config, Some(&src))?,
+        };
+        activate_version(
+            data_dir,
+            config,
+            &activate_opts,
+            &src,
+            &configuration,
+            &target,
+        )?;
+        Ok(Activated {
+            configuration,
+            etag: target.clone(),
+        })
+    });
+
+    // Keep the previously activated version (for rollback), the new one and
+    // a pinned one
+    let protected = [
+        state.last_etag(),
+        etag.as_str(),
+        target.as_str(),
+        config
+            .pinned()
+            .map(|pinned| pinned.etag.as_str())
+            .unwrap_or_default(),
+    ];
+    if let Err(e) = cache.gc(config.max_cached_sources(), &protected) {
+        warn!(error = %e, "Failed to clean up cached sources");
     }
 
-    let tmp_dir = tempfile::TempDir::new()?;
-    self::pull(config.remote()?, tmp_dir.path())?;
-    self::activate_inner(tmp_dir.path(), configuration, activate_opts)?;
+    Ok(FollowCheck {
+        activated: Some(res?),
+        etag,
+        deferred: false,
+    })
+}
 
-    Ok(Some((configuration.to_string(), etag)))
+/// Activate the cached source `src` of the remote version `etag`, recording
+/// it in the history, and recording a failure for backing off
+fn activate_version(
+    data_dir: &DataDir,
+    config: &Config,
+    activate_opts: &ActivateOpts,
+    src: &Path,
+    configuration: &str,
+    etag: &str,
+) -> anyhow::Result<()> {
+    let src = self::import_source(src);
+    let mut entry = history::Entry::start(
+        data_dir,
+        history::ActivationMode::Follow,
+        configuration,
+        Some(etag),
+    );
+    let res = self::activate_inner(
+        &src,
+        configuration,
+        activate_opts,
+        config.plan(),
+        entry.as_mut(),
+    );
+    if let Err(ref e) = res {
+        data_dir.record_activation_failure(configuration, etag, &format!("{e:#}"))?;
+    }
+    if let Some(entry) = entry {
+        entry.finish(&res);
+    }
+    res?;
+    self::keep_active_source(data_dir, &src);
+    Ok(())
 }
@@ -0,0 +1,105 @@
+use std::path::Path;
+use std::{fs, io, process};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::CommandExt as _;
+
+/// Name of the manifest file added to packed sources
+pub const MANIFEST_FILE: &str = ".npcnix-manifest.json";
+
+/// Metadata of a packed source (`.npcnix-manifest.json` inside the archive)
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct Manifest {
+    /// Revision the source was packed from, e.g. a git commit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+}
+
+impl Manifest {
+    /// Manifest of `src`, with the revision detected from git unless given
+    pub fn new(src: &Path, revision: Option<&str>) -> Self {
+        Self {
+            revision: revision
+                .map(ToOwned::to_owned)
+                .or_else(|| git_revision(src)),
+        }
+    }
+
+    /// Manifest of an unpacked source, `None` if it was packed without one
+    pub fn load(src: &Path) -> anyhow::Result<Option<Self>> {
+        let path = src.join(MANIFEST_FILE);
+        match fs::read(&path) {
+            Ok(content) => Ok(Some(
+                serde_json::from_slice(&content)
+                    .with_context(|| format!("Failed to parse {}", path.display()))?,
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+}
+
+fn git_stdout(src: &Path, args: &[&str]) -> Option<String> {
+    let output = process::Command::new("git")
+        .arg("-C")
+        .arg(src)
+        .args(args)
+        .log_debug()
+        .output()
+        .map_err(|e| debug!(error = %e, "Calling `git` failed"))
+        .ok()?;
+    if !output.status.success() {
+        debug!(
+            src = %src.display(),
+            stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+            "Not a git repository"
+        );
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Current git commit of `src` (with a `-dirty` suffix if there are
+/// uncommitted changes), `None` if not a git repository
+fn git_revision(src: &Path) -> Option<String> {
+    let commit = git_stdout(src, &["rev-parse", "HEAD"])?;
+    let dirty = git_stdout(src, &["status", "--porcelain"]).is_some_and(|s| !s.is_empty());
+    Some(if dirty {
+        format!("{commit}-dirty")
+    } else {
+        commit
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_roundtrip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("flake.nix"), "{}").unwrap();
+        // A stale manifest in the source is replaced
+        fs::write(src.join(MANIFEST_FILE), r#"{"revision": "stale"}"#).unwrap();
+
+        let archive = dir.path().join("src.tar.zst");
+        crate::pack(&src, &Default::default(), Some("abc"), &archive).unwrap();
+        let dst = dir.path().join("dst");
+        crate::unpack_archive_to(fs::File::open(&archive).unwrap(), &dst).unwrap();
+
+        assert!(dst.join("flake.nix").exists());
+        assert_eq!(
+            Manifest::load(&dst).unwrap(),
+            Some(Manifest {
+                revision: Some("abc".into())
+            })
+        );
+        assert_eq!(Manifest::load(&src.join("missing")).unwrap(), None);
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::data_dir::DataDir;
+
+/// Upper bounds (in seconds) of the activation duration histogram buckets
+const ACTIVATION_DURATION_BUCKETS: &[f64] = &[30., 60., 120., 300., 600., 1200., 1800., 3600.];
+
+/// Where to export Prometheus metrics
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct MetricsConfig {
+    /// node_exporter textfile collector file (e.g.
+    /// `/var/lib/node_exporter/textfile/npcnix.prom`), rewritten after every
+    /// check
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub textfile: Option<PathBuf>,
+    /// Address to serve metrics over HTTP on; should be a local one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen: Option<SocketAddr>,
+}
+
+impl MetricsConfig {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// Timeout of a single read/write of a metrics scrape
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Limit on the size of a metrics request head
+const MAX_REQUEST_HEAD: u64 = 16 * 1024;
+
+/// Counters collected by the daemon (the rest is derived from the state)
+///
+/// `npcnix follow` exits after each activation (and gets restarted), so they
+/// are persisted in the data dir ([`store_counters`]) and loaded back on
+/// start ([`load_counters`]).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+struct Counters {
+    activation_duration_buckets: [u64; ACTIVATION_DURATION_BUCKETS.len()],
+    activation_duration_sum: f64,
+    activation_duration_count: u64,
+    activations: BTreeMap<String, u64>,
+    download_bytes: u64,
+    remote_errors: BTreeMap<String, u64>,
+}
+
+static COUNTERS: Mutex<Counters> = Mutex::new(Counters {
+    activation_duration_buckets: [0; ACTIVATION_DURATION_BUCKETS.len()],
+    activation_duration_sum: 0.,
+    activation_duration_count: 0,
+    activations: BTreeMap::new(),
+    download_bytes: 0,
+    remote_errors: BTreeMap::new(),
+});
+
+fn with_counters<T>(f: impl FnOnce(&mut Counters) -> T) -> T {
+    f(&mut COUNTERS.lock().expect("not poisoned"))
+}
+
+/// Continue counting from the counters persisted by a previous run
+pub fn load_counters(data_dir: &DataDir) -> anyhow::Result<()> {
+    let path = data_dir.metrics_counters_path();
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    match serde_json::from_slice::<Counters>(&content) {
+        Ok(counters) => with_counters(|current| *current = counters),
+        // E.g. histogram buckets changed; counters just start over
+        Err(e) => warn!(path = %path.display(), error = %e, "Invalid metrics counters; resetting"),
+    }
+    Ok(())
+}
+
+/// Persist the counters, to be picked up by [`load_counters`]
+pub fn store_counters(data_dir: &DataDir) -> anyhow::Result<()> {
+    let counters = with_counters(|counters| counters.clone());
+    crate::misc::store_json_pretty_to_file(&data_dir.metrics_counters_path(), &counters)
+}
+
+pub fn record_activation(duration: Duration, success: bool) {
+    let secs = duration.as_secs_f64();
+    with_counters(|counters| {
+        for (bucket, le) in counters
+            .activation_duration_buckets
+            .iter_mut()
+            .zip(ACTIVATION_DURATION_BUCKETS)
+        {
+            if secs <= *le {
+                *bucket += 1;
+            }
+        }
+        counters.activation_duration_sum += secs;
+        counters.activation_duration_count += 1;
+        *counters
+            .activations
+            .entry(if success { "success" } else { "failure" }.to_owned())
+            .or_default() += 1;
+    });
+}
+
+pub fn record_download_bytes(bytes: u64) {
+    with_counters(|counters| counters.download_bytes += bytes);
+}
+
+/// Count a failed remote operation (`etag`, `fetch`, `control`, ...)
+pub fn record_remote_error(kind: &'static str) {
+    with_counters(|counters| *counters.remote_errors.entry(kind.to_owned()).or_default() += 1);
+}
+
+/// Pass `res` through, counting it as a failed remote operation if it failed
+pub fn track_remote<T>(kind: &'static str, res: anyhow::Result<T>) -> anyhow::Result<T> {
+    if res.is_err() {
+        record_remote_error(kind);
+    }
+    res
+}
+
+/// Reader counting the bytes read as downloaded, and reporting them as the
+/// daemon progress
+pub struct DownloadCounter<R> {
+    inner: R,
+    total: u64,
+}
+
+impl<R> DownloadCounter<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, total: 0 }
+    }
+}
+
+impl<R: Read> Read for DownloadCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        record_download_bytes(len as u64);
+        self.total += len as u64;
+        crate::daemon::set_progress(&format!(
+            "downloaded {:.1} MiB",
+            self.total as f64 / (1024. * 1024.)
+        ));
+        crate::systemd::heartbeat();
+        Ok(len)
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn timestamp(time: chrono::DateTime<chrono::Utc>) -> f64 {
+    time.timestamp_millis() as f64 / 1000.
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render(data_dir: &DataDir) -> anyhow::Result<String> {
+    let config = data_dir.load_config()?;
+    let state = data_dir.load_state()?;
+    let mut out = String::new();
+
+    let mut gauge = |name: &str, help: &str, labels: &str, value: f64| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name}{labels} {value}");
+    };
+    gauge(
+        "npcnix_info",
+        "Currently activated and latest seen remote version",
+        &format!(
+            "{{configuration=\"{}\",etag=\"{}\",remote_etag=\"{}\",version=\"{}\"}}",
+            escape_label(state.last_configuration()),
+            escape_label(state.last_etag()),
+            escape_label(state.remote_etag().unwrap_or_default()),
+            env!("CARGO_PKG_VERSION"),
+        ),
+        1.,
+    );
+    gauge(
+        "npcnix_behind",
+        "Whether the remote changed since the last activation",
+        "",
+        if state.remote_etag().is_some_and(|e| e != state.last_etag()) {
+            1.
+        } else {
+            0.
+        },
+    );
+    if let Some(last_check) = state.last_check() {
+        gauge(
+            "npcnix_last_check_timestamp_seconds",
+            "Time of the last successful check of the remote",
+            "",
+            timestamp(last_check),
+        );
+    }
+    gauge(
+        "npcnix_last_activation_timestamp_seconds",
+        "Time of the last successful activation",
+        "",
+        timestamp(state.last_reconfiguration()),
+    );
+    gauge(
+        "npcnix_consecutive_failures",
+        "Number of failed checks/activations in a row",
+        "",
+        f64::from(state.consecutive_failures()),
+    );
+    gauge(
+        "npcnix_paused",
+        "Whether following the remote is paused",
+        "",
+        if config.is_paused() { 1. } else { 0. },
+    );
+
+    with_counters(|counters| {
+        let name = "npcnix_activation_duration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Duration of activations (kept across daemon restarts)"
+        );
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (count, le) in counters
+            .activation_duration_buckets
+            .iter()
+            .zip(ACTIVATION_DURATION_BUCKETS)
+        {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {count}");
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{le=\"+Inf\"}} {}",
+            counters.activation_duration_count
+        );
+        let _ = writeln!(out, "{name}_sum {}", counters.activation_duration_sum);
+        let _ = writeln!(out, "{name}_count {}", counters.activation_duration_count);
+
+        let name = "npcnix_activations_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Activations by result (kept across daemon restarts)"
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for result in ["success", "failure"] {
+            let count = counters.activations.get(result).copied().unwrap_or(0);
+            let _ = writeln!(out, "{name}{{result=\"{result}\"}} {count}");
+        }
+
+        let name = "npcnix_download_bytes_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Bytes downloaded from the remote (kept across daemon restarts)"
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {}", counters.download_bytes);
+
+        let name = "npcnix_remote_errors_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Failed remote operations by type (kept across daemon restarts)"
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (kind, count) in &counters.remote_errors {
+            let _ = writeln!(out, "{name}{{type=\"{kind}\"}} {count}");
+        }
+    });
+
+    Ok(out)
+}
+
+/// Write the textfile collector file, if enabled in the config
+pub fn publish(data_dir: &DataDir) -> anyhow::Result<()> {
+    let config = data_dir.load_config()?;
+    let Some(path) = config.metrics().textfile.as_deref() else {
+        return Ok(());
+    };
+    crate::misc::store_str_to_file(path, &render(data_dir)?)
+        .with_context(|| format!("Failed to write metrics file: {}", path.display()))
+}
+
+/// Serve metrics over HTTP in a background thread, if enabled in the config
+pub fn serve(data_dir: &DataDir) -> anyhow::Result<()> {
+    let Some(addr) = data_dir.load_config()?.metrics().listen else {
+        return Ok(());
+    };
+    // Metrics are not worth failing the daemon over
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!(%addr, error = %e, "Failed to listen for metrics; not serving them");
+            return Ok(());
+        }
+    };
+    debug!(%addr, "Serving metrics");
+    let data_dir = data_dir.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream
+                .map_err(Into::into)
+                .and_then(|stream| handle(stream, &data_dir))
+            {
+                warn!(error = %e, "Failed to serve metrics");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(stream: TcpStream, data_dir: &DataDir) -> anyhow::Result<()> {
+    // Scrapes are served one at a time, so a stalled client must not block
+    // the others for long
+    let deadline = Instant::now() + CLIENT_TIMEOUT;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    // Any request gets the metrics; just consume the request head
+    for line in io::BufReader::new((&stream).take(MAX_REQUEST_HEAD)).lines() {
+        if line?.is_empty() {
+            break;
+        }
+        if deadline < Instant::now() {
+            bail!("Metrics client too slow");
+        }
+    }
+    let (status, body) = match render(data_dir) {
+        Ok(body) => ("200 OK", body),
+        Err(e) => ("500 Internal Server Error", format!("{e:#}\n")),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_survive_restart() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let data_dir = DataDir::new(dir.path());
+        record_activation(Duration::from_secs(90), true);
+        record_remote_error("etag");
+        store_counters(&data_dir).unwrap();
+        let stored = with_counters(|counters| counters.clone());
+
+        // Like a fresh process
+        with_counters(|counters| {
+            counters.activation_duration_count = 0;
+            counters.activations.clear();
+            counters.remote_errors.clear();
+        });
+        load_counters(&data_dir).unwrap();
+        assert_eq!(with_counters(|counters| counters.clone()), stored);
+        assert!(1 <= stored.activation_duration_count);
+        assert!(1 <= stored.remote_errors["etag"]);
+    }
+}
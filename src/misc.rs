@@ -33,3 +33,124 @@ where
     std::fs::rename(tmp_path, path)?;
     Ok(Ok(()))
 }
+
+/// Parse a human duration like `2h30m`, `90s` or `1d` (units: `d`, `h`, `m`,
+/// `s`)
+pub fn parse_duration(s: &str) -> anyhow::Result<chrono::Duration> {
+    let too_long = || anyhow::format_err!("Duration too long: {s}");
+    let mut total_secs: u64 = 0;
+    let mut num = String::new();
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+        if num.is_empty() {
+            anyhow::bail!("Invalid duration: {s}");
+        }
+        let n: u64 = num.parse().map_err(|_| too_long())?;
+        num.clear();
+        let unit_secs = match c {
+            'd' => 24 * 60 * 60,
+            'h' => 60 * 60,
+            'm' => 60,
+            's' => 1,
+            _ => anyhow::bail!("Invalid duration unit `{c}` in: {s}"),
+        };
+        total_secs = n
+            .checked_mul(unit_secs)
+            .and_then(|secs| total_secs.checked_add(secs))
+            .ok_or_else(too_long)?;
+    }
+    if !num.is_empty() || total_secs == 0 {
+        anyhow::bail!("Invalid duration: {s} (expected e.g. `2h30m`)");
+    }
+    chrono::Duration::from_std(std::time::Duration::from_secs(total_secs)).map_err(|_| too_long())
+}
+
+/// Parse a point in time: RFC 3339 (`2026-11-01T09:00:00Z`), or a shorter
+/// form like `2026-11-01T09:00Z`, `2026-11-01 09:00` or `2026-11-01` (UTC)
+pub fn parse_datetime(s: &str) -> anyhow::Result<chrono::DateTime<chrono::Utc>> {
+    use chrono::TimeZone as _;
+
+    let s = s.trim();
+    if let Ok(datetime) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(datetime.with_timezone(&chrono::Utc));
+    }
+    let naive = s.strip_suffix('Z').unwrap_or(s);
+    for format in ["%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(datetime) = chrono::NaiveDateTime::parse_from_str(naive, format) {
+            return Ok(chrono::Utc.from_utc_datetime(&datetime));
+        }
+    }
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(naive, "%Y-%m-%d") {
+        return Ok(chrono::Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).expect("Valid time")));
+    }
+    anyhow::bail!("Invalid time: {s} (expected e.g. `2026-11-01T09:00Z`)")
+}
+
+/// Name of the user running the command (the original one, under `sudo`)
+pub fn current_user() -> Option<String> {
+    ["SUDO_USER", "USER", "LOGNAME"]
+        .into_iter()
+        .find_map(|var| std::env::var(var).ok().filter(|user| !user.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_valid() {
+        assert_eq!(
+            parse_duration("90s").unwrap(),
+            chrono::Duration::seconds(90)
+        );
+        assert_eq!(
+            parse_duration("2h30m").unwrap(),
+            chrono::Duration::minutes(150)
+        );
+        assert_eq!(parse_duration(" 1d ").unwrap(), chrono::Duration::days(1));
+        assert_eq!(parse_duration("1h1h").unwrap(), chrono::Duration::hours(2));
+    }
+
+    #[test]
+    fn parse_duration_invalid() {
+        for s in ["", "0s", "10", "h", "1x", "1h30", "-1h"] {
+            assert!(parse_duration(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn parse_duration_overflow() {
+        for s in [
+            "99999999999999999999s",
+            "9999999999999999999d",
+            "106751991167300d",
+            "18446744073709551615s1s",
+        ] {
+            assert!(parse_duration(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn parse_datetime_formats() {
+        let expected = chrono::DateTime::parse_from_rfc3339("2026-11-01T09:00:00Z").unwrap();
+        for s in [
+            "2026-11-01T09:00:00Z",
+            "2026-11-01T10:00:00+01:00",
+            "2026-11-01T09:00Z",
+            "2026-11-01T09:00",
+            "2026-11-01 09:00",
+            "2026-11-01 09:00:00",
+        ] {
+            assert_eq!(parse_datetime(s).unwrap(), expected, "{s}");
+        }
+        assert_eq!(
+            parse_datetime("2026-11-01").unwrap(),
+            chrono::DateTime::parse_from_rfc3339("2026-11-01T00:00:00Z").unwrap()
+        );
+        assert!(parse_datetime("tomorrow").is_err());
+        assert!(parse_datetime("2026-13-01").is_err());
+    }
+}
@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// How the follow loop applies remote configurations
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FollowMode {
+    /// Activate (`nixos-rebuild switch`) new remote versions
+    #[default]
+    Activate,
+    /// Only evaluate (or build, if `build` is set) new remote versions and
+    /// report whether the host is up to date; changes are applied by humans
+    Observe {
+        #[serde(default)]
+        build: bool,
+    },
+}
+
+impl FollowMode {
+    pub fn is_observe(self) -> bool {
+        matches!(self, Self::Observe { .. })
+    }
+}
+
+/// Result of evaluating/building a remote version in [`FollowMode::Observe`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct Observation {
+    pub configuration: String,
+    pub etag: String,
+    /// Store path of the system the remote version would activate
+    pub system: PathBuf,
+    /// `system` was built, not only evaluated
+    #[serde(default)]
+    pub built: bool,
+    pub time: chrono::DateTime<chrono::Utc>,
+}
+
+impl Observation {
+    /// The currently running system is the one the remote version describes
+    pub fn is_up_to_date(&self) -> anyhow::Result<bool> {
+        Ok(crate::drift::current_system()?.as_deref() == Some(self.system.as_path()))
+    }
+}
@@ -8,10 +8,22 @@ use crate::data_dir::DataDir;
 pub struct Common {
     #[arg(long, env = "NPCNIX_DATA_DIR", default_value = "/var/lib/npcnix")]
     data_dir: PathBuf,
+
+    /// Read-only declarative config file (TOML, or JSON with `.json`
+    /// extension), taking precedence over `config.json` in the data dir
+    /// (default: `/etc/npcnix/config.toml` or `/etc/npcnix/config.json`, if
+    /// exists)
+    #[arg(long, env = "NPCNIX_DECLARATIVE_CONFIG")]
+    declarative_config: Option<PathBuf>,
 }
 
 impl Common {
     pub fn data_dir(&self) -> DataDir {
-        DataDir::new(&self.data_dir)
+        let data_dir = DataDir::new(&self.data_dir);
+        if let Some(ref path) = self.declarative_config {
+            data_dir.with_declarative_config_path(Some(path))
+        } else {
+            data_dir
+        }
     }
 }
@@ -0,0 +1,312 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{drift, CommandExt as _};
+
+/// Version change of a package between the current and the new system
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct PackageChange {
+    pub name: String,
+    /// As reported by `nix store diff-closures`, e.g. `1.0 → 1.1, +12.3 KiB`
+    pub change: String,
+}
+
+/// Systemd units `switch-to-configuration` would act on
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct UnitChanges {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub start: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub restart: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reload: Vec<String>,
+}
+
+impl UnitChanges {
+    pub fn is_empty(&self) -> bool {
+        self.stop.is_empty()
+            && self.start.is_empty()
+            && self.restart.is_empty()
+            && self.reload.is_empty()
+    }
+
+    fn all(&self) -> [(&'static str, &[String]); 4] {
+        [
+            ("stop", &self.stop),
+            ("start", &self.start),
+            ("restart", &self.restart),
+            ("reload", &self.reload),
+        ]
+    }
+}
+
+/// What switching to a (built) system would change compared to the running
+/// one (`npcnix plan`)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Plan {
+    pub configuration: String,
+    /// Store path of the running system
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current: Option<PathBuf>,
+    /// Store path of the new system
+    pub system: PathBuf,
+    /// Package version changes; not known without a running system
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packages: Option<Vec<PackageChange>>,
+    /// Closure size of the new system, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closure_size: Option<u64>,
+    /// Closure size change, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closure_size_delta: Option<i64>,
+    /// Not known if `switch-to-configuration dry-activate` failed (e.g. when
+    /// not running as root)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub units: Option<UnitChanges>,
+}
+
+impl Plan {
+    /// Compare the built `system` of `configuration` with the running one
+    pub fn compute(configuration: &str, system: &Path) -> anyhow::Result<Self> {
+        let current = drift::current_system()?;
+        let closure_size = closure_size(system)?;
+        let (packages, closure_size_delta) = match current {
+            Some(ref current) => (
+                Some(diff_closures(current, system)?),
+                Some(closure_size as i64 - self::closure_size(current)? as i64),
+            ),
+            None => (None, None),
+        };
+        let units = match dry_activate(system) {
+            Ok(units) => Some(units),
+            Err(e) => {
+                warn!(error = %e, "Failed to determine unit changes");
+                None
+            }
+        };
+        Ok(Self {
+            configuration: configuration.to_owned(),
+            current,
+            system: system.to_owned(),
+            packages,
+            closure_size: Some(closure_size),
+            closure_size_delta,
+            units,
+        })
+    }
+
+    pub fn is_noop(&self) -> bool {
+        self.current.as_deref() == Some(self.system.as_path())
+    }
+
+    /// One-line summary of the plan
+    pub fn summary(&self) -> String {
+        if self.is_noop() {
+            return "no changes".into();
+        }
+        let mut parts = vec![];
+        if let Some(ref packages) = self.packages {
+            parts.push(format!("{} package changes", packages.len()));
+        }
+        if let Some(delta) = self.closure_size_delta {
+            parts.push(format!("closure {}", fmt_size_delta(delta)));
+        }
+        if let Some(ref units) = self.units {
+            for (action, units) in units.all() {
+                if !units.is_empty() {
+                    parts.push(format!("{} units to {action}", units.len()));
+                }
+            }
+        }
+        if parts.is_empty() {
+            return "changes unknown".into();
+        }
+        parts.join(", ")
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "plan for {}: {} -> {}",
+            self.configuration,
+            self.current
+                .as_deref()
+                .map(|current| current.display().to_string())
+                .unwrap_or_else(|| "none".into()),
+            self.system.display()
+        )?;
+        if self.is_noop() {
+            return writeln!(f, "no changes");
+        }
+        if let Some(size) = self.closure_size {
+            writeln!(
+                f,
+                "closure size: {}{}",
+                fmt_size(size),
+                self.closure_size_delta
+                    .map(|delta| format!(" ({})", fmt_size_delta(delta)))
+                    .unwrap_or_default()
+            )?;
+        }
+        if let Some(ref packages) = self.packages {
+            writeln!(f, "package changes: {}", packages.len())?;
+            for package in packages {
+                writeln!(f, "  {}: {}", package.name, package.change)?;
+            }
+        }
+        match self.units {
+            Some(ref units) if units.is_empty() => writeln!(f, "units: no changes")?,
+            Some(ref units) => {
+                for (action, units) in units.all() {
+                    if !units.is_empty() {
+                        writeln!(f, "units to {action}: {}", units.join(", "))?;
+                    }
+                }
+            }
+            None => writeln!(f, "units: unknown")?,
+        }
+        Ok(())
+    }
+}
+
+fn fmt_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while 1024. <= value && unit + 1 < UNITS.len() {
+        value /= 1024.;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+fn fmt_size_delta(delta: i64) -> String {
+    format!(
+        "{}{}",
+        if delta < 0 { "-" } else { "+" },
+        fmt_size(delta.unsigned_abs())
+    )
+}
+
+/// Remove terminal escape sequences (colors) from `nix` output
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn diff_closures(current: &Path, system: &Path) -> anyhow::Result<Vec<PackageChange>> {
+    let mut cmd = crate::nix_cmd();
+    cmd.args(["store", "diff-closures"])
+        .arg(current)
+        .arg(system);
+    Ok(strip_ansi(&crate::run_nix_for_stdout(cmd, None)?)
+        .lines()
+        .filter_map(|line| {
+            let (name, change) = line.split_once(": ")?;
+            Some(PackageChange {
+                name: name.trim().to_owned(),
+                change: change.trim().to_owned(),
+            })
+        })
+        .collect())
+}
+
+fn closure_size(path: &Path) -> anyhow::Result<u64> {
+    let mut cmd = crate::nix_cmd();
+    cmd.args(["path-info", "--closure-size"]).arg(path);
+    let out = crate::run_nix_for_stdout(cmd, None)?;
+    out.split_whitespace()
+        .last()
+        .and_then(|size| size.parse().ok())
+        .with_context(|| format!("Invalid `nix path-info` output: {out}"))
+}
+
+/// Ask the new system's `switch-to-configuration` which units it would touch
+fn dry_activate(system: &Path) -> anyhow::Result<UnitChanges> {
+    let output = process::Command::new(system.join("bin/switch-to-configuration"))
+        .arg("dry-activate")
+        .log_debug()
+        .output()
+        .context("Calling `switch-to-configuration` failed")?;
+    if !output.status.success() {
+        bail!(
+            "switch-to-configuration returned exit code={:?}: {}",
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(parse_dry_activate(
+        &String::from_utf8_lossy(&output.stderr),
+        &String::from_utf8_lossy(&output.stdout),
+    ))
+}
+
+/// Parse `would <action> the following units: a, b` lines of
+/// `switch-to-configuration dry-activate` output
+fn parse_dry_activate(stderr: &str, stdout: &str) -> UnitChanges {
+    let mut units = UnitChanges::default();
+    for line in stderr.lines().chain(stdout.lines()) {
+        let Some(rest) = line.strip_prefix("would ") else {
+            continue;
+        };
+        let Some((action, list)) = rest.split_once(" the following units: ") else {
+            continue;
+        };
+        let list = list.split(", ").map(|unit| unit.trim().to_owned());
+        match action {
+            "stop" => units.stop.extend(list),
+            "start" => units.start.extend(list),
+            "restart" => units.restart.extend(list),
+            "reload" => units.reload.extend(list),
+            _ => {}
+        }
+    }
+    units
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dry_activate_output() {
+        let stderr = "\
+would stop the following units: old.service
+would restart systemd
+would start the following units: a.service, b.timer
+would restart the following units: nginx.service
+would activate the configuration...
+";
+        let stdout = "would reload the following units: dbus.service\n";
+        let units = parse_dry_activate(stderr, stdout);
+        assert_eq!(units.stop, ["old.service"]);
+        assert_eq!(units.start, ["a.service", "b.timer"]);
+        assert_eq!(units.restart, ["nginx.service"]);
+        assert_eq!(units.reload, ["dbus.service"]);
+        assert!(parse_dry_activate("would activate the configuration...", "").is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+use std::{fmt, io, process};
+
+use anyhow::{bail, Context};
+use chrono::{NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{booted_system_path, current_system_path, shutdown_path, CommandExt};
+
+/// System components that are not applied by `nixos-rebuild switch` and
+/// require a reboot to take effect
+const BOOT_COMPONENTS: &[&str] = &["kernel", "initrd", "kernel-modules", "systemd"];
+
+/// What to do when an activation changed any of [`BOOT_COMPONENTS`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum RebootPolicy {
+    /// Only report that reboot is required
+    #[default]
+    Never,
+    /// Reboot when inside a daily (UTC) maintenance window
+    MaintenanceWindow {
+        start: NaiveTime,
+        duration_mins: u64,
+    },
+    /// Reboot right after the activation, after a delay
+    Immediately { delay_mins: u64 },
+}
+
+impl RebootPolicy {
+    pub fn is_in_window(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        match self {
+            RebootPolicy::MaintenanceWindow {
+                start,
+                duration_mins,
+            } => {
+                let day_mins = 24 * 60;
+                let now_mins = i64::from(now.time().num_seconds_from_midnight() / 60);
+                let start_mins = i64::from(start.num_seconds_from_midnight() / 60);
+                let since_start = (now_mins - start_mins).rem_euclid(day_mins);
+                // windows longer than a day are effectively always open
+                since_start < i64::try_from(*duration_mins).unwrap_or(i64::MAX)
+            }
+            RebootPolicy::Never | RebootPolicy::Immediately { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for RebootPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RebootPolicy::Never => f.write_str("never"),
+            RebootPolicy::MaintenanceWindow {
+                start,
+                duration_mins,
+            } => write!(
+                f,
+                "maintenance window ({} UTC, {duration_mins}m)",
+                start.format("%H:%M")
+            ),
+            RebootPolicy::Immediately { delay_mins } => {
+                write!(f, "immediately (after {delay_mins}m)")
+            }
+        }
+    }
+}
+
+fn canonicalize_opt(path: &Path) -> anyhow::Result<Option<PathBuf>> {
+    match path.canonicalize() {
+        Ok(path) => Ok(Some(path)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to resolve {}", path.display())),
+    }
+}
+
+/// Names of the boot components that differ between the booted and the
+/// currently activated system
+pub fn changed_boot_components() -> anyhow::Result<Vec<&'static str>> {
+    let booted = booted_system_path();
+    let current = current_system_path();
+
+    let mut changed = vec![];
+    for component in BOOT_COMPONENTS {
+        if canonicalize_opt(&booted.join(component))? != canonicalize_opt(&current.join(component))?
+        {
+            changed.push(*component);
+        }
+    }
+    Ok(changed)
+}
+
+pub fn is_reboot_required() -> anyhow::Result<bool> {
+    Ok(!changed_boot_components()?.is_empty())
+}
+
+pub fn schedule_reboot(delay_mins: u64) -> anyhow::Result<()> {
+    info!(delay_mins, "Scheduling reboot");
+    let status = process::Command::new(shutdown_path())
+        .args([
+            "-r",
+            &if delay_mins == 0 {
+                "now".to_string()
+            } else {
+                format!("+{delay_mins}")
+            },
+            "npcnix: rebooting to apply new kernel/initrd/systemd",
+        ])
+        .log_debug()
+        .status()
+        .context("Calling `shutdown` failed")?;
+    if !status.success() {
+        bail!("shutdown returned exit code={:?}", status.code());
+    }
+    Ok(())
+}
+
+/// Handle reboot policy right after a successful activation
+pub fn handle_after_activation(policy: RebootPolicy) -> anyhow::Result<()> {
+    let changed = changed_boot_components()?;
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    match policy {
+        RebootPolicy::Never => {
+            warn!(components = ?changed, "Reboot required");
+        }
+        RebootPolicy::MaintenanceWindow { .. } => {
+            info!(components = ?changed, %policy, "Reboot required, waiting for maintenance window");
+            handle_periodic(policy)?;
+        }
+        RebootPolicy::Immediately { delay_mins } => {
+            schedule_reboot(delay_mins)?;
+        }
+    }
+    Ok(())
+}
+
+/// Handle reboot policy on every iteration of the follow loop
+pub fn handle_periodic(policy: RebootPolicy) -> anyhow::Result<()> {
+    if policy.is_in_window(chrono::Utc::now()) && is_reboot_required()? {
+        schedule_reboot(0)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(&format!("2026-10-18T{time}Z"))
+            .unwrap()
+            .into()
+    }
+
+    fn window(start: &str, duration_mins: u64) -> RebootPolicy {
+        RebootPolicy::MaintenanceWindow {
+            start: NaiveTime::parse_from_str(start, "%H:%M").unwrap(),
+            duration_mins,
+        }
+    }
+
+    #[test]
+    fn window_within_day() {
+        let policy = window("02:00", 60);
+        assert!(!policy.is_in_window(at("01:59:59")));
+        assert!(policy.is_in_window(at("02:00:00")));
+        assert!(policy.is_in_window(at("02:59:59")));
+        assert!(!policy.is_in_window(at("03:00:00")));
+    }
+
+    #[test]
+    fn window_across_midnight() {
+        let policy = window("23:30", 60);
+        assert!(policy.is_in_window(at("23:45:00")));
+        assert!(policy.is_in_window(at("00:15:00")));
+        assert!(!policy.is_in_window(at("00:30:00")));
+        assert!(!policy.is_in_window(at("12:00:00")));
+    }
+
+    #[test]
+    fn window_edge_cases() {
+        assert!(!window("02:00", 0).is_in_window(at("02:00:00")));
+        assert!(window("02:00", 24 * 60).is_in_window(at("01:59:00")));
+        assert!(window("02:00", u64::MAX).is_in_window(at("01:59:00")));
+        assert!(!RebootPolicy::Never.is_in_window(at("02:00:00")));
+        assert!(!RebootPolicy::Immediately { delay_mins: 0 }.is_in_window(at("02:00:00")));
+    }
+}
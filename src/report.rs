@@ -0,0 +1,208 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::cache::SourceCache;
+use crate::data_dir::DataDir;
+use crate::state::StateError;
+use crate::{drift, host, manifest, store};
+
+/// Directory (relative to the remote) where hosts publish their reports
+pub const REPORTS_DIR: &str = "reports/";
+
+/// Status of a single host, published to `reports/<host-id>.json` next to
+/// the remote
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Report {
+    pub host_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    pub configuration: String,
+    /// Etag of the last activated remote version
+    pub etag: String,
+    /// Manifest revision (e.g. git commit) of the last activated remote
+    /// version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// Etag of the remote seen during the last check
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+    pub last_reconfiguration: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_check: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<StateError>,
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    #[serde(default)]
+    pub paused: bool,
+    /// Running system differs from the one activated by npcnix
+    #[serde(default)]
+    pub drifted: bool,
+    /// Host follows the remote in observe-only mode
+    #[serde(default)]
+    pub observe: bool,
+    /// Observe-only mode: running system matches the observed remote version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub up_to_date: Option<bool>,
+    pub npcnix_version: String,
+    pub time: chrono::DateTime<chrono::Utc>,
+}
+
+impl Report {
+    pub fn collect(data_dir: &DataDir) -> anyhow::Result<Self> {
+        let config = data_dir.load_config()?;
+        let state = data_dir.load_state()?;
+        Ok(Self {
+            host_id: host::host_id()?,
+            hostname: host::hostname().ok(),
+            configuration: state.last_configuration().to_owned(),
+            etag: state.last_etag().to_owned(),
+            revision: last_revision(data_dir, state.last_etag()),
+            remote_etag: state.remote_etag().map(ToOwned::to_owned),
+            generation: state.current_generation(),
+            last_reconfiguration: state.last_reconfiguration(),
+            last_check: state.last_check(),
+            last_error: state.last_error().cloned(),
+            consecutive_failures: state.consecutive_failures(),
+            paused: config.is_paused(),
+            drifted: drift::detect(&state)?.is_some(),
+            observe: config.mode().is_observe(),
+            up_to_date: match state.observation() {
+                Some(observation) if config.mode().is_observe() => {
+                    Some(observation.is_up_to_date()?)
+                }
+                _ => None,
+            },
+            npcnix_version: env!("CARGO_PKG_VERSION").to_owned(),
+            time: chrono::Utc::now(),
+        })
+    }
+
+    pub fn status_str(&self) -> &'static str {
+        if 0 < self.consecutive_failures {
+            "failing"
+        } else if self.drifted {
+            "drifted"
+        } else if self.paused {
+            "paused"
+        } else if self.observe {
+            match self.up_to_date {
+                Some(true) => "ok",
+                _ => "behind",
+            }
+        } else if self.remote_etag.as_deref().is_some_and(|e| e != self.etag) {
+            "behind"
+        } else {
+            "ok"
+        }
+    }
+}
+
+/// Manifest revision of the cached source of `etag`, if known
+fn last_revision(data_dir: &DataDir, etag: &str) -> Option<String> {
+    if etag.is_empty() {
+        return None;
+    }
+    let src = SourceCache::new(data_dir).peek(etag)?;
+    manifest::Manifest::load(&src)
+        .map_err(|e| warn!(error = %e, "Failed to load the source manifest"))
+        .ok()
+        .flatten()?
+        .revision
+}
+
+pub fn report_url(remote: &Url, host_id: &str) -> anyhow::Result<Url> {
+    store::dir_url(remote, &format!("{REPORTS_DIR}{host_id}.json"))
+}
+
+/// Publish the report of this host, if enabled in the config
+pub fn publish(data_dir: &DataDir) -> anyhow::Result<()> {
+    let config = data_dir.load_config()?;
+    if !config.report() {
+        return Ok(());
+    }
+    let report = Report::collect(data_dir)?;
+    let url = report_url(config.remote()?, &report.host_id)?;
+    debug!(%url, "Publishing report");
+    store::put_json(&url, &report, config.region_opt())
+}
+
+/// Aggregated reports of all hosts in a store
+#[derive(Serialize, Debug, Clone)]
+pub struct FleetStatus {
+    pub reports: Vec<Report>,
+}
+
+impl FleetStatus {
+    /// Fetch all reports from a `store` (the "directory" containing remotes;
+    /// the trailing `/` is optional)
+    pub fn fetch(store_url: &Url, region: Option<&str>) -> anyhow::Result<Self> {
+        let prefix = store::dir_url(&store::as_dir_url(store_url), REPORTS_DIR)?;
+        let mut reports = vec![];
+        for url in store::list_objects(&prefix, region)? {
+            if let Some(report) = store::get_json::<Report>(&url, region)? {
+                reports.push(report);
+            }
+        }
+        reports.sort_by(|a, b| {
+            (a.hostname.as_deref(), &a.host_id).cmp(&(b.hostname.as_deref(), &b.host_id))
+        });
+        Ok(Self { reports })
+    }
+}
+
+/// Abbreviated revision (e.g. git commit), keeping a `-dirty` suffix
+fn short_revision(revision: &str) -> String {
+    let (commit, suffix) = match revision.strip_suffix("-dirty") {
+        Some(commit) => (commit, "-dirty"),
+        None => (revision, ""),
+    };
+    format!("{}{suffix}", commit.chars().take(12).collect::<String>())
+}
+
+impl fmt::Display for FleetStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<24} {:<20} {:<36} {:<18} {:<8} {:<20} {:<20} ERROR",
+            "HOST", "CONFIGURATION", "ETAG", "REVISION", "STATUS", "LAST ACTIVATION", "LAST REPORT"
+        )?;
+        for report in &self.reports {
+            writeln!(
+                f,
+                "{:<24} {:<20} {:<36} {:<18} {:<8} {:<20} {:<20} {}",
+                report.hostname.as_deref().unwrap_or(&report.host_id),
+                report.configuration,
+                report.etag,
+                report
+                    .revision
+                    .as_deref()
+                    .map(short_revision)
+                    .unwrap_or_else(|| "-".into()),
+                report.status_str(),
+                report
+                    .last_reconfiguration
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                report
+                    .time
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                if 0 < report.consecutive_failures {
+                    report
+                        .last_error
+                        .as_ref()
+                        .map(|e| e.message.lines().next().unwrap_or_default())
+                        .unwrap_or_default()
+                } else {
+                    ""
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
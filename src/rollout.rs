@@ -0,0 +1,173 @@
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::store;
+
+/// Suffix of the rollout document object, next to the remote
+pub const ROLLOUT_SUFFIX: &str = ".rollout.json";
+
+pub fn rollout_url(remote: &Url) -> Url {
+    store::sibling_url(remote, ROLLOUT_SUFFIX)
+}
+
+/// Rollout policy of a new remote version (`<remote>.rollout.json`)
+///
+/// A host takes the new version if it is on the `hosts` allowlist, or if its
+/// stable bucket (derived from the host id) falls under the current
+/// percentage. Without a `start`, the percentage is just `percentage`. With a
+/// `start`, it grows linearly from `percentage` to 100% over `ramp_hours`,
+/// and no host (other than allowlisted ones) is selected before `start`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct Rollout {
+    /// Remote etag this rollout applies to; if set and different from the
+    /// current remote etag, the rollout document is considered stale and
+    /// ignored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percentage: Option<f64>,
+    /// Host ids or hostnames that always take the new version
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ramp_hours: Option<u64>,
+}
+
+impl Rollout {
+    /// Missing rollout document means no rollout
+    ///
+    /// Any other failure (including access denied) is an error, so the host
+    /// stays on its current version instead of skipping the canary stage.
+    pub fn fetch(remote: &Url, region: Option<&str>) -> anyhow::Result<Option<Self>> {
+        store::get_json(&rollout_url(remote), region)
+    }
+
+    pub fn store(&self, remote: &Url, region: Option<&str>) -> anyhow::Result<()> {
+        store::put_json(&rollout_url(remote), self, region)
+    }
+
+    pub fn applies_to(&self, etag: &str) -> bool {
+        self.etag.as_deref().map_or(true, |e| e == etag)
+    }
+
+    /// Percentage of the fleet that should take the new version at `now`
+    pub fn cur_percentage(&self, now: chrono::DateTime<chrono::Utc>) -> f64 {
+        let Some(start) = self.start else {
+            return self.percentage.unwrap_or(100.0).clamp(0.0, 100.0);
+        };
+        if now < start {
+            return 0.0;
+        }
+        let initial = self.percentage.unwrap_or(0.0).clamp(0.0, 100.0);
+        let ratio = match self.ramp_hours {
+            Some(ramp_hours) if ramp_hours != 0 => ((now - start).num_seconds() as f64
+                / (ramp_hours.saturating_mul(60 * 60)) as f64)
+                .clamp(0.0, 1.0),
+            _ => 1.0,
+        };
+        initial + (100.0 - initial) * ratio
+    }
+
+    /// Stable position of the host in `[0, 100)`, for a given rollout
+    pub fn host_bucket(&self, host_id: &str) -> f64 {
+        let mut hasher = Md5::new();
+        hasher.update(host_id.as_bytes());
+        hasher.update(b":");
+        hasher.update(self.etag.as_deref().unwrap_or_default().as_bytes());
+        let hash = hasher.finalize();
+        let value = u64::from_be_bytes(hash[..8].try_into().expect("Can't fail"));
+        (value % 10_000) as f64 / 100.0
+    }
+
+    pub fn includes_host(&self, host_id: &str, hostname: Option<&str>) -> bool {
+        if self
+            .hosts
+            .iter()
+            .any(|h| h == host_id || Some(h.as_str()) == hostname)
+        {
+            return true;
+        }
+        self.host_bucket(host_id) < self.cur_percentage(chrono::Utc::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    #[test]
+    fn cur_percentage_fixed() {
+        let now = at("2026-10-18T12:00:00Z");
+        assert_eq!(Rollout::default().cur_percentage(now), 100.0);
+        let rollout = Rollout {
+            percentage: Some(25.0),
+            ..Default::default()
+        };
+        assert_eq!(rollout.cur_percentage(now), 25.0);
+        let rollout = Rollout {
+            percentage: Some(150.0),
+            ..Default::default()
+        };
+        assert_eq!(rollout.cur_percentage(now), 100.0);
+    }
+
+    #[test]
+    fn cur_percentage_ramp() {
+        let rollout = Rollout {
+            percentage: Some(10.0),
+            start: Some(at("2026-10-18T12:00:00Z")),
+            ramp_hours: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(rollout.cur_percentage(at("2026-10-18T11:59:59Z")), 0.0);
+        assert_eq!(rollout.cur_percentage(at("2026-10-18T12:00:00Z")), 10.0);
+        assert_eq!(rollout.cur_percentage(at("2026-10-18T17:00:00Z")), 55.0);
+        assert_eq!(rollout.cur_percentage(at("2026-10-18T22:00:00Z")), 100.0);
+        assert_eq!(rollout.cur_percentage(at("2026-10-20T00:00:00Z")), 100.0);
+
+        let rollout = Rollout {
+            start: Some(at("2026-10-18T12:00:00Z")),
+            ..Default::default()
+        };
+        assert_eq!(rollout.cur_percentage(at("2026-10-18T11:00:00Z")), 0.0);
+        assert_eq!(rollout.cur_percentage(at("2026-10-18T12:00:00Z")), 100.0);
+    }
+
+    #[test]
+    fn host_bucket() {
+        let rollout = Rollout {
+            etag: Some("abc".into()),
+            ..Default::default()
+        };
+        let buckets: Vec<f64> = (0..1000)
+            .map(|i| rollout.host_bucket(&format!("host-{i}")))
+            .collect();
+        assert!(buckets.iter().all(|b| (0.0..100.0).contains(b)));
+        // Stable for a given host and rollout
+        assert_eq!(rollout.host_bucket("host-1"), buckets[1]);
+        // Roughly uniform
+        let below_half = buckets.iter().filter(|b| **b < 50.0).count();
+        assert!((400..600).contains(&below_half), "{below_half}");
+        // Reshuffled for a different version
+        let other = Rollout {
+            etag: Some("def".into()),
+            ..Default::default()
+        };
+        assert!((0..1000).any(|i| other.host_bucket(&format!("host-{i}")) != buckets[i]));
+    }
+
+    #[test]
+    fn fetch_missing() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let remote = Url::from_file_path(dir.path().join("main")).unwrap();
+        assert!(Rollout::fetch(&remote, None).unwrap().is_none());
+    }
+}
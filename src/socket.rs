@@ -0,0 +1,298 @@
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::MetadataExt as _;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, thread};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::config::PauseInfo;
+use crate::daemon::{self, DaemonStatus, Wake};
+use crate::data_dir::DataDir;
+
+/// Request to the daemon's control socket (`npcnix.sock` in the data dir)
+///
+/// Each connection carries a single JSON request line, answered with a single
+/// JSON [`Response`] line. The status includes the current phase and its
+/// progress (download size, or the last line of `nix`/`nixos-rebuild`
+/// output).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    /// Check the remote immediately
+    Trigger,
+    Pause {
+        /// Pause expiry; paused indefinitely if not set
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until: Option<chrono::DateTime<chrono::Utc>>,
+        #[serde(flatten)]
+        info: PauseInfo,
+    },
+    Unpause,
+    /// Abort the current fetch/build
+    Abort,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Status(DaemonStatus),
+    Error { message: String },
+}
+
+/// How long a client may take to send its request or read the response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Listening control socket; removed on drop
+#[derive(Debug)]
+pub struct Server {
+    path: PathBuf,
+    /// Inode of the socket we bound, so we never remove someone else's
+    ino: u64,
+}
+
+impl Server {
+    /// Start serving requests in a background thread
+    ///
+    /// Fails if another daemon is still listening on the socket.
+    pub fn start(data_dir: &DataDir, wake: &Wake) -> anyhow::Result<Self> {
+        let path = data_dir.socket_path();
+        match UnixStream::connect(&path) {
+            Ok(_) => bail!(
+                "Control socket {} is in use by another npcnix daemon",
+                path.display()
+            ),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) => {}
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to check control socket: {}", path.display()))
+            }
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("Failed to remove stale control socket"),
+        }
+        // Create the socket with restrictive permissions right away, instead
+        // of fixing them up after it is already reachable
+        let prev_umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(&path);
+        unsafe { libc::umask(prev_umask) };
+        let listener = listener
+            .with_context(|| format!("Failed to bind control socket: {}", path.display()))?;
+        let ino = fs::metadata(&path)?.ino();
+
+        let data_dir = data_dir.clone();
+        let wake = wake.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream.map_err(Into::into).and_then(|stream| {
+                    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+                    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+                    handle(stream, &data_dir, &wake)
+                });
+                if let Err(e) = res {
+                    warn!(error = %e, "Control socket request failed");
+                }
+            }
+        });
+        Ok(Self { path, ino })
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        match fs::metadata(&self.path) {
+            Ok(metadata) if metadata.ino() == self.ino => {}
+            Ok(_) => {
+                warn!(path = %self.path.display(), "Control socket was replaced; not removing");
+                return;
+            }
+            Err(e) => {
+                warn!(path = %self.path.display(), error = %e, "Failed to check control socket");
+                return;
+            }
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), error = %e, "Failed to remove control socket");
+        }
+    }
+}
+
+fn handle(stream: UnixStream, data_dir: &DataDir, wake: &Wake) -> anyhow::Result<()> {
+    let mut line = String::new();
+    io::BufReader::new(&stream).read_line(&mut line)?;
+    // Invalid requests (e.g. unknown commands of a newer client) get an error
+    // response too, instead of a closed connection
+    let response = match serde_json::from_str::<Request>(&line)
+        .context("Invalid request")
+        .and_then(|request| {
+            debug!(?request, "Control socket request");
+            process(request, data_dir, wake)
+        }) {
+        Ok(response) => response,
+        Err(e) => Response::Error {
+            message: format!("{e:#}"),
+        },
+    };
+    let mut stream = &stream;
+    serde_json::to_writer(stream, &response)?;
+    stream.write_all(b"\n")?;
+    Ok(())
+}
+
+fn process(request: Request, data_dir: &DataDir, wake: &Wake) -> anyhow::Result<Response> {
+    Ok(match request {
+        Request::Status => Response::Status(daemon::status()),
+        Request::Trigger => {
+            wake.trigger();
+            Response::Ok
+        }
+        Request::Pause { until, info } => {
+            data_dir.update_config(|config| {
+                Ok(match until {
+                    Some(until) => config.with_paused_until(until, info),
+                    None => config.with_paused_indefinitely(info),
+                })
+            })?;
+            Response::Ok
+        }
+        Request::Unpause => {
+            data_dir.update_config(|config| Ok(config.with_unpaused()))?;
+            // Resume right away
+            wake.trigger();
+            Response::Ok
+        }
+        Request::Abort => {
+            daemon::abort()?;
+            Response::Ok
+        }
+    })
+}
+
+/// Send `request` to the daemon; `None` if the daemon is not running
+pub fn request(path: &Path, request: &Request) -> anyhow::Result<Option<Response>> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e).context("Failed to connect to the control socket"),
+    };
+    let mut writer = &stream;
+    serde_json::to_writer(writer, request)?;
+    writer.write_all(b"\n")?;
+    let mut line = String::new();
+    io::BufReader::new(&stream).read_line(&mut line)?;
+    Ok(Some(
+        serde_json::from_str(&line).context("Invalid response")?,
+    ))
+}
+
+/// Like [`request`], but turns an error response into an error
+pub fn request_ok(path: &Path, request: &Request) -> anyhow::Result<Option<Response>> {
+    match self::request(path, request)? {
+        Some(Response::Error { message }) => bail!("{message}"),
+        response => Ok(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn raw_request(path: &Path, line: &str) -> Response {
+        let stream = UnixStream::connect(path).unwrap();
+        let mut writer = &stream;
+        writer.write_all(line.as_bytes()).unwrap();
+        let mut response = String::new();
+        io::BufReader::new(&stream)
+            .read_line(&mut response)
+            .unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn request_format() {
+        let request: Request = serde_json::from_str(
+            r#"{"command": "pause", "until": "2026-10-19T12:00:00Z", "reason": "maintenance"}"#,
+        )
+        .unwrap();
+        let Request::Pause { until, info } = request else {
+            panic!("not a pause: {request:?}");
+        };
+        assert_eq!(until.unwrap().to_rfc3339(), "2026-10-19T12:00:00+00:00");
+        assert_eq!(info.reason.as_deref(), Some("maintenance"));
+
+        assert_eq!(
+            serde_json::to_string(&Request::Trigger).unwrap(),
+            r#"{"command":"trigger"}"#
+        );
+        assert!(serde_json::from_str::<Request>(r#"{"command": "frobnicate"}"#).is_err());
+    }
+
+    #[test]
+    fn protocol() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let data_dir = DataDir::new(dir.path());
+        let wake = Wake::new();
+        let server = Server::start(&data_dir, &wake).unwrap();
+        let path = data_dir.socket_path();
+
+        // Only one daemon may serve the socket
+        assert!(Server::start(&data_dir, &wake).is_err());
+
+        match request_ok(&path, &Request::Status).unwrap().unwrap() {
+            Response::Status(status) => assert_eq!(status.pid, std::process::id()),
+            response => panic!("unexpected response: {response:?}"),
+        }
+
+        for line in ["{\"command\": \"frobnicate\"}\n", "not json\n"] {
+            assert!(
+                matches!(raw_request(&path, line), Response::Error { ref message } if message.starts_with("Invalid request")),
+                "{line}"
+            );
+        }
+        assert!(matches!(
+            request_ok(&path, &Request::Abort),
+            Err(e) if e.to_string().starts_with("Nothing to abort")
+        ));
+
+        request_ok(
+            &path,
+            &Request::Pause {
+                until: None,
+                info: PauseInfo::new(Some("testing")),
+            },
+        )
+        .unwrap();
+        assert!(data_dir.load_config().unwrap().is_paused());
+
+        request_ok(&path, &Request::Unpause).unwrap();
+        assert!(!data_dir.load_config().unwrap().is_paused());
+        // Unpausing also wakes up the follow loop
+        let start = Instant::now();
+        wake.sleep(Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        drop(server);
+        assert!(!path.exists());
+        assert!(request(&path, &Request::Status).unwrap().is_none());
+    }
+}
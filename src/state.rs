@@ -0,0 +1,342 @@
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::observe::Observation;
+
+/// How many distinct failed etags to remember
+const MAX_ACTIVATION_FAILURES: usize = 16;
+
+/// Record of failed attempts to activate (or, in observe-only mode, to
+/// evaluate) a given remote etag
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct ActivationFailure {
+    pub configuration: String,
+    pub etag: String,
+    pub count: u32,
+    pub last_error: String,
+    pub last_attempt: chrono::DateTime<chrono::Utc>,
+}
+
+impl ActivationFailure {
+    /// Exponential backoff: `base_secs * 2^(count - 1)`, capped at `max_secs`
+    pub fn backoff(&self, base_secs: u64, max_secs: u64) -> chrono::Duration {
+        let secs = base_secs
+            .saturating_mul(
+                1u64.checked_shl(self.count.saturating_sub(1))
+                    .unwrap_or(u64::MAX),
+            )
+            .min(max_secs);
+        chrono::Duration::from_std(std::time::Duration::from_secs(secs))
+            .unwrap_or(chrono::Duration::MAX)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct StateError {
+    pub message: String,
+    pub time: chrono::DateTime<chrono::Utc>,
+}
+
+/// Daemon runtime state (`/var/lib/npcnix/state.json`)
+///
+/// Unlike [`Config`] this is only ever written by npcnix itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct State {
+    /// Time of the last successful activation
+    #[serde(default = "Utc::now")]
+    last_reconfiguration: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    last_etag: String,
+    #[serde(default)]
+    last_configuration: String,
+
+    /// NixOS system profile generation after the last successful activation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current_generation: Option<u64>,
+    /// Store path of the system after the last successful activation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current_system: Option<PathBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_error: Option<StateError>,
+    #[serde(default)]
+    consecutive_failures: u32,
+
+    /// Time of the last successful check of the remote
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_check: Option<chrono::DateTime<chrono::Utc>>,
+    /// Remote etag seen during the last successful check
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    remote_etag: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    activation_failures: Vec<ActivationFailure>,
+
+    /// Last remote version evaluated in observe-only mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    observation: Option<Observation>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            last_reconfiguration: chrono::Utc::now(),
+            last_etag: "".into(),
+            last_configuration: "".into(),
+            current_generation: None,
+            current_system: None,
+            last_error: None,
+            consecutive_failures: 0,
+            last_check: None,
+            remote_etag: None,
+            activation_failures: vec![],
+            observation: None,
+        }
+    }
+}
+
+impl State {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(serde_json::from_reader::<_, Self>(fs::File::open(path)?)?)
+    }
+
+    /// Load the state from a `config.json` written by a version of npcnix that
+    /// kept the runtime state there
+    pub fn load_legacy(config_path: &Path) -> anyhow::Result<Option<Self>> {
+        let file = match fs::File::open(config_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_json::from_reader::<_, Self>(file)?))
+    }
+
+    pub fn store(&self, path: &Path) -> anyhow::Result<()> {
+        crate::misc::store_json_pretty_to_file(path, self)
+    }
+
+    pub fn with_updated_last_reconfiguration(
+        self,
+        configuration: &str,
+        etag: &str,
+        current_generation: Option<u64>,
+        current_system: Option<PathBuf>,
+    ) -> Self {
+        let mut activation_failures = self.activation_failures;
+        activation_failures.retain(|f| !(f.configuration == configuration && f.etag == etag));
+        Self {
+            last_configuration: configuration.to_owned(),
+            last_etag: etag.to_owned(),
+            last_reconfiguration: chrono::Utc::now(),
+            current_generation,
+            current_system,
+            consecutive_failures: 0,
+            activation_failures,
+            ..self
+        }
+    }
+
+    pub fn with_error(self, error: &str) -> Self {
+        Self {
+            last_error: Some(StateError {
+                message: error.to_owned(),
+                time: chrono::Utc::now(),
+            }),
+            consecutive_failures: self.consecutive_failures.saturating_add(1),
+            ..self
+        }
+    }
+
+    /// Record a successful check of the remote; `reset_failures` only if the
+    /// host was found (or made) up to date
+    pub fn with_check_succeeded(self, remote_etag: &str, reset_failures: bool) -> Self {
+        Self {
+            consecutive_failures: if reset_failures {
+                0
+            } else {
+                self.consecutive_failures
+            },
+            last_check: Some(chrono::Utc::now()),
+            remote_etag: Some(remote_etag.to_owned()),
+            ..self
+        }
+    }
+
+    pub fn with_activation_failure(self, configuration: &str, etag: &str, error: &str) -> Self {
+        let mut activation_failures = self.activation_failures;
+        let count = activation_failures
+            .iter()
+            .position(|f| f.configuration == configuration && f.etag == etag)
+            .map(|i| activation_failures.remove(i).count)
+            .unwrap_or(0);
+        activation_failures.push(ActivationFailure {
+            configuration: configuration.to_owned(),
+            etag: etag.to_owned(),
+            count: count.saturating_add(1),
+            last_error: error.to_owned(),
+            last_attempt: chrono::Utc::now(),
+        });
+        let excess = activation_failures
+            .len()
+            .saturating_sub(MAX_ACTIVATION_FAILURES);
+        activation_failures.drain(..excess);
+        Self {
+            activation_failures,
+            ..self
+        }
+    }
+
+    pub fn with_observation(self, observation: Observation) -> Self {
+        let mut activation_failures = self.activation_failures;
+        activation_failures.retain(|f| {
+            !(f.configuration == observation.configuration && f.etag == observation.etag)
+        });
+        Self {
+            observation: Some(observation),
+            activation_failures,
+            ..self
+        }
+    }
+
+    /// Find a failure record for `etag`; with `configuration` of `None`
+    /// (not known yet) any configuration matches
+    pub fn activation_failure(
+        &self,
+        configuration: Option<&str>,
+        etag: &str,
+    ) -> Option<&ActivationFailure> {
+        self.activation_failures.iter().find(|f| {
+            configuration.map_or(true, |configuration| f.configuration == configuration)
+                && f.etag == etag
+        })
+    }
+
+    /// If activation of `etag` failed before and is still backing off, returns
+    /// the time of the next retry
+    pub fn activation_retry_at(
+        &self,
+        config: &Config,
+        configuration: Option<&str>,
+        etag: &str,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        let failure = self.activation_failure(configuration, etag)?;
+        let retry_at = failure
+            .last_attempt
+            .checked_add_signed(
+                failure.backoff(config.max_sleep_secs(), config.max_failure_backoff_secs()),
+            )
+            .unwrap_or(chrono::DateTime::<Utc>::MAX_UTC);
+        (Utc::now() < retry_at).then_some(retry_at)
+    }
+
+    pub fn last_reconfiguration(&self) -> chrono::DateTime<chrono::Utc> {
+        self.last_reconfiguration
+    }
+
+    pub fn last_configuration(&self) -> &str {
+        &self.last_configuration
+    }
+
+    pub fn last_etag(&self) -> &str {
+        &self.last_etag
+    }
+
+    pub fn current_generation(&self) -> Option<u64> {
+        self.current_generation
+    }
+
+    pub fn current_system(&self) -> Option<&Path> {
+        self.current_system.as_deref()
+    }
+
+    pub fn last_error(&self) -> Option<&StateError> {
+        self.last_error.as_ref()
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    pub fn last_check(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.last_check
+    }
+
+    pub fn remote_etag(&self) -> Option<&str> {
+        self.remote_etag.as_deref()
+    }
+
+    pub fn observation(&self) -> Option<&Observation> {
+        self.observation.as_ref()
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&serde_json::to_string_pretty(self).map_err(|_e| fmt::Error)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(count: u32) -> ActivationFailure {
+        ActivationFailure {
+            configuration: "host".into(),
+            etag: "abc".into(),
+            count,
+            last_error: "failed".into(),
+            last_attempt: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let secs = |count| failure(count).backoff(60, 3600).num_seconds();
+        assert_eq!(secs(0), 60);
+        assert_eq!(secs(1), 60);
+        assert_eq!(secs(2), 120);
+        assert_eq!(secs(3), 240);
+        assert_eq!(secs(6), 1920);
+        assert_eq!(secs(7), 3600);
+    }
+
+    #[test]
+    fn backoff_saturates() {
+        assert_eq!(failure(100).backoff(60, 3600).num_seconds(), 3600);
+        assert_eq!(failure(u32::MAX).backoff(60, 3600).num_seconds(), 3600);
+        // Must not panic on absurd settings
+        assert_eq!(
+            failure(u32::MAX).backoff(u64::MAX, u64::MAX),
+            chrono::Duration::MAX
+        );
+    }
+
+    #[test]
+    fn observation_clears_failure() {
+        let state = State::default()
+            .with_activation_failure("host", "abc", "eval failed")
+            .with_activation_failure("host", "abc", "eval failed");
+        assert_eq!(
+            state.activation_failure(Some("host"), "abc").unwrap().count,
+            2
+        );
+        assert!(state.activation_failure(None, "abc").is_some());
+
+        let state = state.with_observation(Observation {
+            configuration: "host".into(),
+            etag: "abc".into(),
+            system: "/nix/store/abc-system".into(),
+            built: false,
+            time: Utc::now(),
+        });
+        assert!(state.activation_failure(None, "abc").is_none());
+    }
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::config::{ConfigPause, ConfigPinned};
+use crate::daemon::{DaemonStatus, Phase};
+use crate::data_dir::DataDir;
+use crate::drift::Drift;
+use crate::observe::{FollowMode, Observation};
+use crate::state::StateError;
+use crate::{drift, host, reboot, socket};
+
+/// Detailed status of npcnix on this host (`npcnix status`)
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct HostStatus {
+    /// `active` or `paused (...)`
+    pub status: String,
+    pub remote: Option<String>,
+    /// Configuration that would be activated now
+    pub configuration: Option<String>,
+    pub mode: FollowMode,
+    pub last_configuration: String,
+    pub last_etag: String,
+    pub last_reconfiguration: chrono::DateTime<chrono::Utc>,
+    pub generation: Option<u64>,
+    /// Store path of the running system
+    pub current_system: Option<PathBuf>,
+    /// Etag of the remote seen during the last check
+    pub remote_etag: Option<String>,
+    pub last_check: Option<chrono::DateTime<chrono::Utc>>,
+    /// The remote has a different version than the activated one
+    pub update_available: bool,
+    pub next_check: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_error: Option<StateError>,
+    pub consecutive_failures: u32,
+    pub paused: Option<ConfigPause>,
+    pub pinned: Option<ConfigPinned>,
+    pub drift: Option<Drift>,
+    pub observation: Option<Observation>,
+    pub up_to_date: Option<bool>,
+    /// Boot components changed since boot
+    pub reboot_required: Vec<&'static str>,
+    /// Running daemon, if any
+    pub daemon: Option<DaemonStatus>,
+}
+
+impl HostStatus {
+    pub fn collect(data_dir: &DataDir) -> anyhow::Result<Self> {
+        let config = data_dir.load_config()?;
+        let state = data_dir.load_state()?;
+        let daemon = match socket::request(&data_dir.socket_path(), &socket::Request::Status)? {
+            Some(socket::Response::Status(status)) => Some(status),
+            _ => None,
+        };
+        let observation = state.observation().cloned();
+        let up_to_date = match observation {
+            Some(ref observation) if config.mode().is_observe() => {
+                Some(observation.is_up_to_date()?)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            status: config.status_string(),
+            remote: config.remote().ok().map(ToString::to_string),
+            configuration: host::select_configuration(&config, None).ok(),
+            mode: config.mode(),
+            last_configuration: state.last_configuration().to_owned(),
+            last_etag: state.last_etag().to_owned(),
+            last_reconfiguration: state.last_reconfiguration(),
+            generation: state.current_generation(),
+            current_system: drift::current_system()?,
+            remote_etag: state.remote_etag().map(ToOwned::to_owned),
+            last_check: state.last_check(),
+            update_available: state
+                .remote_etag()
+                .is_some_and(|etag| etag != state.last_etag()),
+            next_check: daemon.as_ref().and_then(|daemon| match daemon.phase {
+                Phase::Sleeping { until } => Some(until),
+                _ => None,
+            }),
+            last_error: state.last_error().cloned(),
+            consecutive_failures: state.consecutive_failures(),
+            paused: config.paused().cloned(),
+            pinned: config.pinned().cloned(),
+            drift: drift::detect(&state)?,
+            observation,
+            up_to_date,
+            reboot_required: reboot::changed_boot_components()?,
+            daemon,
+        })
+    }
+}
+
+fn fmt_time(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+impl fmt::Display for HostStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.status)?;
+        writeln!(f, "remote: {}", self.remote.as_deref().unwrap_or("not set"))?;
+        writeln!(
+            f,
+            "configuration: {}",
+            self.configuration.as_deref().unwrap_or("unknown")
+        )?;
+        if self.mode.is_observe() {
+            writeln!(f, "mode: observe-only")?;
+        }
+        if self.last_etag.is_empty() {
+            writeln!(f, "activated: nothing yet")?;
+        } else {
+            writeln!(
+                f,
+                "activated: {} of {} at {}{}",
+                self.last_etag,
+                self.last_configuration,
+                fmt_time(self.last_reconfiguration),
+                self.generation
+                    .map(|generation| format!(" (generation {generation})"))
+                    .unwrap_or_default()
+            )?;
+        }
+        if let Some(ref current_system) = self.current_system {
+            writeln!(f, "current system: {}", current_system.display())?;
+        }
+        writeln!(
+            f,
+            "remote etag: {}{}",
+            self.remote_etag.as_deref().unwrap_or("unknown"),
+            self.last_check
+                .map(|time| format!(" (checked at {})", fmt_time(time)))
+                .unwrap_or_default()
+        )?;
+        if self.update_available {
+            writeln!(f, "update available")?;
+        }
+        if let Some(next_check) = self.next_check {
+            writeln!(f, "next check: {}", fmt_time(next_check))?;
+        }
+        if let Some(ref pinned) = self.pinned {
+            writeln!(
+                f,
+                "pinned to {} since {}",
+                pinned.etag,
+                fmt_time(pinned.since)
+            )?;
+        }
+        if 0 < self.consecutive_failures {
+            writeln!(f, "consecutive failures: {}", self.consecutive_failures)?;
+        }
+        if let Some(ref error) = self.last_error {
+            writeln!(
+                f,
+                "last error at {}: {}",
+                fmt_time(error.time),
+                error.message.lines().next().unwrap_or_default()
+            )?;
+        }
+        if let Some(ref drift) = self.drift {
+            writeln!(
+                f,
+                "drifted (activated: {}; current: {})",
+                drift.expected.display(),
+                drift.actual.display()
+            )?;
+        }
+        if self.mode.is_observe() {
+            match self.observation {
+                Some(ref observation) => writeln!(
+                    f,
+                    "observe-only: {} (remote {} of {}: {})",
+                    if self.up_to_date == Some(true) {
+                        "up to date"
+                    } else {
+                        "not up to date"
+                    },
+                    observation.etag,
+                    observation.configuration,
+                    observation.system.display(),
+                )?,
+                None => writeln!(f, "observe-only: not observed yet")?,
+            }
+        }
+        if !self.reboot_required.is_empty() {
+            writeln!(
+                f,
+                "reboot required (changed: {})",
+                self.reboot_required.join(", ")
+            )?;
+        }
+        match self.daemon {
+            Some(ref daemon) => writeln!(
+                f,
+                "daemon (pid {}): {}{}",
+                daemon.pid,
+                daemon.phase,
+                daemon
+                    .progress
+                    .as_ref()
+                    .map(|progress| format!(" ({progress})"))
+                    .unwrap_or_default()
+            )?,
+            None => writeln!(f, "daemon: not running")?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,437 @@
+use std::path::PathBuf;
+use std::process::{self, Stdio};
+use std::{fs, io};
+
+use anyhow::{bail, format_err, Context};
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{aws_cli_path, CommandExt};
+
+/// Url of an object next to the `remote`, with `suffix` appended to its name
+///
+/// E.g. `s3://bucket/remotes/main` + `.rollout.json` =
+/// `s3://bucket/remotes/main.rollout.json`
+pub fn sibling_url(remote: &Url, suffix: &str) -> Url {
+    let mut url = remote.clone();
+    url.set_path(&format!("{}{suffix}", remote.path()));
+    url
+}
+
+/// Url relative to the "directory" containing the `remote`
+///
+/// E.g. `s3://bucket/remotes/main` + `reports/a.json` =
+/// `s3://bucket/remotes/reports/a.json`
+pub fn dir_url(remote: &Url, rel: &str) -> anyhow::Result<Url> {
+    Ok(remote.join(rel)?)
+}
+
+/// `url` as a "directory" (with a trailing `/`), so relative urls are joined
+/// onto it, not onto its parent
+///
+/// E.g. `s3://bucket/remotes` -> `s3://bucket/remotes/`
+pub fn as_dir_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url
+}
+
+fn file_path(url: &Url) -> anyhow::Result<PathBuf> {
+    url.to_file_path()
+        .map_err(|_| format_err!("Invalid file url: {url}"))
+}
+
+fn s3_not_found(stderr: &[u8]) -> bool {
+    let stderr = String::from_utf8_lossy(stderr);
+    stderr.contains("(404)") || stderr.contains("NoSuchKey") || stderr.contains("Not Found")
+}
+
+fn aws_region_args(region: Option<&str>) -> Vec<&str> {
+    if let Some(region) = region {
+        vec!["--region", region]
+    } else {
+        vec![]
+    }
+}
+
+/// Read a whole object, `None` if it does not exist
+///
+/// Only "not found" counts as not existing; access denied is an error, like
+/// any other failure.
+pub fn get_object(url: &Url, region: Option<&str>) -> anyhow::Result<Option<Vec<u8>>> {
+    match url.scheme() {
+        "s3" => {
+            let output = process::Command::new(aws_cli_path())
+                .args(["s3", "cp", url.as_str(), "-"])
+                .args(aws_region_args(region))
+                .log_debug()
+                .output()
+                .context("`aws` cli failed")?;
+            if output.status.success() {
+                Ok(Some(output.stdout))
+            } else if s3_not_found(&output.stderr) {
+                Ok(None)
+            } else {
+                bail!(
+                    "aws s3 cp returned code={:?} stderr={}",
+                    output.status.code(),
+                    String::from_utf8_lossy(&output.stderr),
+                )
+            }
+        }
+        "file" => match fs::read(file_path(url)?) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        },
+        scheme => bail!("Protocol not supported: {scheme}"),
+    }
+}
+
+/// Write a whole object, replacing existing one
+pub fn put_object(url: &Url, content: &[u8], region: Option<&str>) -> anyhow::Result<()> {
+    match url.scheme() {
+        "s3" => {
+            let mut child = process::Command::new(aws_cli_path())
+                .args(["s3", "cp", "-", url.as_str()])
+                .args(aws_region_args(region))
+                .stdin(Stdio::piped())
+                .log_debug()
+                .spawn()
+                .context("`aws` cli failed")?;
+            io::Write::write_all(&mut child.stdin.take().expect("piped"), content)?;
+            let status = child.wait()?;
+            if !status.success() {
+                bail!("aws s3 cp returned code={:?}", status.code());
+            }
+            Ok(())
+        }
+        "file" => {
+            crate::misc::store_to_file_with(&file_path(url)?, |f| f.write_all(content))??;
+            Ok(())
+        }
+        scheme => bail!("Protocol not supported: {scheme}"),
+    }
+}
+
+fn s3_bucket_key(url: &Url) -> anyhow::Result<(&str, &str)> {
+    Ok((
+        url.host_str().ok_or_else(|| format_err!("Invalid URL"))?,
+        url.path()
+            .split_once('/')
+            .ok_or_else(|| format_err!("Path doesn't start with a /"))?
+            .1,
+    ))
+}
+
+#[derive(Deserialize)]
+struct GetObjectResponse {
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+/// Read a whole object along with its etag, `None` if it does not exist
+///
+/// For `file://` urls the etag is the md5 of the content.
+pub fn get_object_with_etag(
+    url: &Url,
+    region: Option<&str>,
+) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+    match url.scheme() {
+        "s3" => {
+            let (bucket, key) = s3_bucket_key(url)?;
+            let body = tempfile::NamedTempFile::new()?;
+            let output = process::Command::new(aws_cli_path())
+                .args(["s3api", "get-object", "--bucket", bucket, "--key", key])
+                .arg(body.path())
+                .args(aws_region_args(region))
+                .log_debug()
+                .output()
+                .context("`aws` cli failed")?;
+            if !output.status.success() {
+                if s3_not_found(&output.stderr) {
+                    return Ok(None);
+                }
+                bail!(
+                    "aws s3api get-object returned code={:?} stderr={}",
+                    output.status.code(),
+                    String::from_utf8_lossy(&output.stderr),
+                )
+            }
+            let resp: GetObjectResponse = serde_json::from_slice(&output.stdout)?;
+            Ok(Some((fs::read(body.path())?, resp.etag)))
+        }
+        "file" => match fs::read(file_path(url)?) {
+            Ok(content) => {
+                let etag = file_etag(&content);
+                Ok(Some((content, etag)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        },
+        scheme => bail!("Protocol not supported: {scheme}"),
+    }
+}
+
+fn file_etag(content: &[u8]) -> String {
+    format!("\"{:x}\"", Md5::digest(content))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Condition<'a> {
+    Absent,
+    Match(&'a str),
+}
+
+/// Create an object only if it does not exist yet
+///
+/// Returns `false` if the object already existed. Relies on S3 conditional
+/// writes (`If-None-Match`) for `s3://`.
+pub fn put_object_if_absent(
+    url: &Url,
+    content: &[u8],
+    region: Option<&str>,
+) -> anyhow::Result<bool> {
+    put_object_conditional(url, content, Condition::Absent, region)
+}
+
+/// Replace an object only if its etag is still `etag`
+///
+/// Returns `false` if the object was changed (or deleted) in the meantime.
+pub fn put_object_if_match(
+    url: &Url,
+    content: &[u8],
+    etag: &str,
+    region: Option<&str>,
+) -> anyhow::Result<bool> {
+    put_object_conditional(url, content, Condition::Match(etag), region)
+}
+
+fn put_object_conditional(
+    url: &Url,
+    content: &[u8],
+    condition: Condition,
+    region: Option<&str>,
+) -> anyhow::Result<bool> {
+    match url.scheme() {
+        "s3" => {
+            let (bucket, key) = s3_bucket_key(url)?;
+            let mut body = tempfile::NamedTempFile::new()?;
+            io::Write::write_all(&mut body, content)?;
+            let condition = match condition {
+                Condition::Absent => ["--if-none-match", "*"],
+                Condition::Match(etag) => ["--if-match", etag],
+            };
+            let output = process::Command::new(aws_cli_path())
+                .args(["s3api", "put-object", "--bucket", bucket, "--key", key])
+                .args(condition)
+                .arg("--body")
+                .arg(body.path())
+                .args(aws_region_args(region))
+                .log_debug()
+                .output()
+                .context("`aws` cli failed")?;
+            if output.status.success() {
+                return Ok(true);
+            }
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.contains("PreconditionFailed")
+                || stderr.contains("ConditionalRequestConflict")
+                || s3_not_found(&output.stderr)
+            {
+                return Ok(false);
+            }
+            bail!(
+                "aws s3api put-object returned code={:?} stderr={}",
+                output.status.code(),
+                stderr,
+            )
+        }
+        "file" => {
+            let path = file_path(url)?;
+            let dir = path.parent().expect("Not a root path");
+            fs::create_dir_all(dir)?;
+            // All conditional writers of the directory serialize on its lock;
+            // the content itself is still replaced atomically, for readers
+            let mut lock = fd_lock::RwLock::new(fs::File::open(dir)?);
+            let _guard = lock.write()?;
+            let current = match fs::read(&path) {
+                Ok(current) => Some(file_etag(&current)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            let matches = match condition {
+                Condition::Absent => current.is_none(),
+                Condition::Match(etag) => current.as_deref() == Some(etag),
+            };
+            if !matches {
+                return Ok(false);
+            }
+            crate::misc::store_to_file_with(&path, |f| f.write_all(content))??;
+            Ok(true)
+        }
+        scheme => bail!("Protocol not supported: {scheme}"),
+    }
+}
+
+/// Delete an object; not an error if it does not exist
+pub fn delete_object(url: &Url, region: Option<&str>) -> anyhow::Result<()> {
+    match url.scheme() {
+        "s3" => {
+            let output = process::Command::new(aws_cli_path())
+                .args(["s3", "rm", url.as_str()])
+                .args(aws_region_args(region))
+                .log_debug()
+                .output()
+                .context("`aws` cli failed")?;
+            if !output.status.success() && !s3_not_found(&output.stderr) {
+                bail!(
+                    "aws s3 rm returned code={:?} stderr={}",
+                    output.status.code(),
+                    String::from_utf8_lossy(&output.stderr),
+                )
+            }
+            Ok(())
+        }
+        "file" => match fs::remove_file(file_path(url)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        },
+        scheme => bail!("Protocol not supported: {scheme}"),
+    }
+}
+
+#[derive(Deserialize)]
+struct ListObjectsResponse {
+    #[serde(rename = "Contents", default)]
+    contents: Vec<ListObjectsEntry>,
+}
+
+#[derive(Deserialize)]
+struct ListObjectsEntry {
+    #[serde(rename = "Key")]
+    key: String,
+}
+
+/// List urls of all objects directly under the `prefix` (a url ending with
+/// `/`)
+pub fn list_objects(prefix: &Url, region: Option<&str>) -> anyhow::Result<Vec<Url>> {
+    match prefix.scheme() {
+        "s3" => {
+            let (bucket, key_prefix) = s3_bucket_key(prefix)?;
+            let output = process::Command::new(aws_cli_path())
+                .args([
+                    "s3api",
+                    "list-objects-v2",
+                    "--bucket",
+                    bucket,
+                    "--prefix",
+                    key_prefix,
+                ])
+                .args(aws_region_args(region))
+                .log_debug()
+                .output()
+                .context("`aws` cli failed")?;
+            if !output.status.success() {
+                bail!(
+                    "aws s3api list-objects-v2 returned code={:?} stderr={}",
+                    output.status.code(),
+                    String::from_utf8_lossy(&output.stderr),
+                )
+            }
+            // empty listing produces no output at all
+            if output.stdout.iter().all(u8::is_ascii_whitespace) {
+                return Ok(vec![]);
+            }
+            let resp: ListObjectsResponse = serde_json::from_slice(&output.stdout)?;
+            resp.contents
+                .into_iter()
+                .filter(|entry| !entry.key[key_prefix.len()..].contains('/'))
+                .map(|entry| Ok(Url::parse(&format!("s3://{bucket}/{}", entry.key))?))
+                .collect()
+        }
+        "file" => {
+            let dir = file_path(prefix)?;
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+                Err(e) => return Err(e.into()),
+            };
+            let mut urls = vec![];
+            for entry in entries {
+                let entry = entry?;
+                if entry.file_type()?.is_file() {
+                    urls.push(
+                        Url::from_file_path(entry.path())
+                            .map_err(|_| format_err!("Invalid path"))?,
+                    );
+                }
+            }
+            urls.sort();
+            Ok(urls)
+        }
+        scheme => bail!("Protocol not supported: {scheme}"),
+    }
+}
+
+pub fn get_json<T>(url: &Url, region: Option<&str>) -> anyhow::Result<Option<T>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    get_object(url, region)?
+        .map(|content| {
+            serde_json::from_slice(&content).with_context(|| format!("Failed to parse {url}"))
+        })
+        .transpose()
+}
+
+pub fn put_json<T>(url: &Url, val: &T, region: Option<&str>) -> anyhow::Result<()>
+where
+    T: serde::Serialize,
+{
+    put_object(url, &serde_json::to_vec_pretty(val)?, region)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn s3_errors() {
+        let not_found = b"fatal error: An error occurred (404) when calling the HeadObject operation: Key \"a\" does not exist";
+        let forbidden = b"fatal error: An error occurred (403) when calling the HeadObject operation: Forbidden";
+        assert!(s3_not_found(not_found));
+        assert!(!s3_not_found(forbidden));
+    }
+
+    #[test]
+    fn as_dir_url() {
+        for url in ["s3://bucket/prefix", "s3://bucket/prefix/"] {
+            let dir = super::as_dir_url(&Url::parse(url).unwrap());
+            assert_eq!(
+                dir_url(&dir, "reports/").unwrap().as_str(),
+                "s3://bucket/prefix/reports/"
+            );
+        }
+    }
+
+    #[test]
+    fn file_conditional_put() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let url = Url::from_file_path(dir.path().join("a/b.json")).unwrap();
+        assert!(get_object_with_etag(&url, None).unwrap().is_none());
+        assert!(!put_object_if_match(&url, b"x", "\"etag\"", None).unwrap());
+        assert!(put_object_if_absent(&url, b"1", None).unwrap());
+        assert!(!put_object_if_absent(&url, b"2", None).unwrap());
+
+        let (content, etag) = get_object_with_etag(&url, None).unwrap().unwrap();
+        assert_eq!(content, b"1");
+        assert!(put_object_if_match(&url, b"3", &etag, None).unwrap());
+        // etag is stale now
+        assert!(!put_object_if_match(&url, b"4", &etag, None).unwrap());
+        assert_eq!(get_object(&url, None).unwrap().unwrap(), b"3");
+    }
+}
@@ -0,0 +1,84 @@
+use std::os::linux::net::SocketAddrExt as _;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tracing::{debug, warn};
+
+use crate::daemon::{self, Phase};
+
+/// Time of the last sign of progress of the follow loop
+static LAST_HEARTBEAT: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn send(message: &str) -> anyhow::Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let path = path.to_string_lossy();
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path.as_ref())?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .send_to_addr(message.as_bytes(), &addr)
+        .with_context(|| format!("Failed to notify systemd at {path}"))?;
+    Ok(())
+}
+
+/// Send an `sd_notify` message (e.g. `READY=1`), if running under systemd
+/// with `Type=notify`
+pub fn notify(message: &str) {
+    if let Err(e) = send(message) {
+        warn!(error = %e, "sd_notify failed");
+    }
+}
+
+/// Record that the follow loop is making progress
+pub fn heartbeat() {
+    *LAST_HEARTBEAT.lock().expect("not poisoned") = Some(Instant::now());
+}
+
+/// Watchdog interval requested by systemd (`WatchdogSec=`), if any
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = std::env::var_os("WATCHDOG_PID") {
+        if pid.to_str()?.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (0 < usec).then(|| Duration::from_micros(usec))
+}
+
+/// Ping the systemd watchdog in the background, as long as the follow loop is
+/// alive
+///
+/// Sleeping and running `nix`/`nixos-rebuild` (which can legitimately take
+/// long) always count as alive. Other phases (like talking to the remote)
+/// have to make progress within the watchdog interval, so e.g. a hung `aws`
+/// call gets the daemon restarted.
+pub fn start_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    debug!(?interval, "Starting watchdog");
+    heartbeat();
+    thread::spawn(move || loop {
+        thread::sleep(interval / 2);
+        let long_running = matches!(
+            daemon::status().phase,
+            Phase::Sleeping { .. } | Phase::Evaluating | Phase::Building | Phase::Switching
+        );
+        let recent = LAST_HEARTBEAT
+            .lock()
+            .expect("not poisoned")
+            .is_some_and(|last| last.elapsed() < interval);
+        if long_running || recent {
+            notify("WATCHDOG=1");
+        } else {
+            warn!("Follow loop not making progress; skipping watchdog ping");
+        }
+    });
+}
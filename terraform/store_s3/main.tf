@@ -45,6 +45,42 @@ resource "aws_iam_policy" "iam_policy" {
         "Resource" : [
           "arn:aws:s3:::${var.bucket.id}/${var.prefix}/*"
         ]
+      },
+      {
+        "Sid" : "ConfigNixosLeases",
+        "Effect" : "Allow",
+        "Action" : [
+          "s3:PutObject",
+          "s3:DeleteObject",
+        ],
+        "Resource" : [
+          "arn:aws:s3:::${var.bucket.id}/${var.prefix}/leases/*"
+        ]
+      },
+      {
+        "Sid" : "ConfigNixosReports",
+        "Effect" : "Allow",
+        "Action" : [
+          "s3:PutObject",
+        ],
+        "Resource" : [
+          "arn:aws:s3:::${var.bucket.id}/${var.prefix}/reports/*"
+        ]
+      },
+      {
+        "Sid" : "ConfigNixosList",
+        "Effect" : "Allow",
+        "Action" : [
+          "s3:ListBucket",
+        ],
+        "Resource" : [
+          "arn:aws:s3:::${var.bucket.id}"
+        ],
+        "Condition" : {
+          "StringLike" : {
+            "s3:prefix" : ["${var.prefix}/*"]
+          }
+        }
       }
     ]
   })